@@ -3,6 +3,9 @@
 # Number of runs for each configuration
 RUNS=10
 
+# Noise seed, so every configuration filters the same noisy image
+SEED=${SEED:-42}
+
 # Clear previous results
 echo "Clearing previous results..."
 rm -f results/results.csv
@@ -15,27 +18,53 @@ echo ""
 echo "=== Sequential (seq) ==="
 for i in $(seq 1 $RUNS); do
     echo "  Run $i/$RUNS - kernel 3x3..."
-    ./target/release/median-filter -i image.jpg -o results/output_seq_3.jpg -n 0.1 -m seq -k 3 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_seq_3.jpg -n 0.1 -m seq -k 3 --seed $SEED > /dev/null
     echo "  Run $i/$RUNS - kernel 5x5..."
-    ./target/release/median-filter -i image.jpg -o results/output_seq_5.jpg -n 0.1 -m seq -k 5 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_seq_5.jpg -n 0.1 -m seq -k 5 --seed $SEED > /dev/null
 done
 
 # Parallel benchmarks
 echo "=== Parallel (par) ==="
 for i in $(seq 1 $RUNS); do
     echo "  Run $i/$RUNS - kernel 3x3..."
-    ./target/release/median-filter -i image.jpg -o results/output_par_3.jpg -n 0.1 -m par -k 3 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_par_3.jpg -n 0.1 -m par -k 3 --seed $SEED > /dev/null
     echo "  Run $i/$RUNS - kernel 5x5..."
-    ./target/release/median-filter -i image.jpg -o results/output_par_5.jpg -n 0.1 -m par -k 5 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_par_5.jpg -n 0.1 -m par -k 5 --seed $SEED > /dev/null
+done
+
+# Median algorithm comparison (sequential and parallel)
+for method in seq par; do
+    for alg in sort constant-time huang simd; do
+        echo "=== $method with $alg algorithm ==="
+        for i in $(seq 1 $RUNS); do
+            echo "  Run $i/$RUNS - kernel 3x3..."
+            ./target/release/median-filter -i image.jpg -o results/output_${method}_${alg}_3.jpg -n 0.1 -m $method -k 3 --algorithm $alg --seed $SEED > /dev/null
+            echo "  Run $i/$RUNS - kernel 5x5..."
+            ./target/release/median-filter -i image.jpg -o results/output_${method}_${alg}_5.jpg -n 0.1 -m $method -k 5 --algorithm $alg --seed $SEED > /dev/null
+        done
+    done
+done
+
+# Adaptive median against the plain median at high noise levels
+for method in seq par; do
+    for noise in 0.3 0.5; do
+        echo "=== $method adaptive vs plain median, noise $noise ==="
+        for i in $(seq 1 $RUNS); do
+            echo "  Run $i/$RUNS - plain 3x3..."
+            ./target/release/median-filter -i image.jpg -o results/output_${method}_plain_${noise}.jpg -n $noise -m $method -k 3 --seed $SEED > /dev/null
+            echo "  Run $i/$RUNS - adaptive 3x3 up to 7x7..."
+            ./target/release/median-filter -i image.jpg -o results/output_${method}_adaptive_${noise}.jpg -n $noise -m $method -k 3 --adaptive 7 --seed $SEED > /dev/null
+        done
+    done
 done
 
 # GPU benchmarks
 echo "=== GPU (gpu) ==="
 for i in $(seq 1 $RUNS); do
     echo "  Run $i/$RUNS - kernel 3x3..."
-    ./target/release/median-filter -i image.jpg -o results/output_gpu_3.jpg -n 0.1 -m gpu -k 3 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_gpu_3.jpg -n 0.1 -m gpu -k 3 --seed $SEED > /dev/null
     echo "  Run $i/$RUNS - kernel 5x5..."
-    ./target/release/median-filter -i image.jpg -o results/output_gpu_5.jpg -n 0.1 -m gpu -k 5 > /dev/null
+    ./target/release/median-filter -i image.jpg -o results/output_gpu_5.jpg -n 0.1 -m gpu -k 5 --seed $SEED > /dev/null
 done
 
 # Distributed benchmarks with different process counts
@@ -43,9 +72,9 @@ for np in 2 4 8; do
     echo "=== Distributed (dist) with $np processes ==="
     for i in $(seq 1 $RUNS); do
         echo "  Run $i/$RUNS - kernel 3x3..."
-        mpirun -np $np ./target/release/median-filter -i image.jpg -o results/output_dist_${np}_3.jpg -n 0.1 -m dist -k 3 > /dev/null
+        mpirun -np $np ./target/release/median-filter -i image.jpg -o results/output_dist_${np}_3.jpg -n 0.1 -m dist -k 3 --seed $SEED > /dev/null
         echo "  Run $i/$RUNS - kernel 5x5..."
-        mpirun -np $np ./target/release/median-filter -i image.jpg -o results/output_dist_${np}_5.jpg -n 0.1 -m dist -k 5 > /dev/null
+        mpirun -np $np ./target/release/median-filter -i image.jpg -o results/output_dist_${np}_5.jpg -n 0.1 -m dist -k 5 --seed $SEED > /dev/null
     done
 done
 
@@ -0,0 +1,124 @@
+//! Adaptive median filter.
+//!
+//! A fixed window either leaves impulses in place when noise is dense (the
+//! median itself is an impulse) or blurs detail when it is large enough to
+//! remove them. The adaptive median (Gonzalez & Woods) grows the window per
+//! pixel until its median is not an impulse, and replaces a pixel only if
+//! the pixel itself is one, so uncorrupted pixels keep their value.
+
+use crate::backend::FilterSpec;
+use crate::kernel::Kernel;
+use crate::sample::Sample;
+use crate::selection::select_median;
+use crate::shared::Plane;
+use std::ops::Range;
+
+/// Windows the adaptive median tries, from the spec's kernel up to
+/// `max_size`
+///
+/// Each window is two pixels wider and taller than the previous one and
+/// keeps the kernel's shape; the last one is at most `max_size` in either
+/// dimension.
+pub fn adaptive_kernels(kernel: &Kernel, max_size: usize) -> Vec<Kernel> {
+    let steps = max_size.saturating_sub(kernel.width().max(kernel.height())) / 2;
+    (0..=steps)
+        .map(|i| Kernel::with_shape(kernel.shape(), kernel.width() + 2 * i, kernel.height() + 2 * i))
+        .collect()
+}
+
+/// Adaptive-median-filter rows `rows` of `plane`, growing the window up to
+/// `max_size`
+///
+/// For each pixel, the smallest window whose median lies strictly between
+/// the window's minimum and maximum is used; the pixel is kept unless it is
+/// itself the minimum or maximum, in which case it becomes the median. If no
+/// window up to `max_size` qualifies, the median of the largest is used.
+/// Like `shared::filter_rows`, returns the samples of those rows in
+/// row-major order.
+pub fn adaptive_rows<T: Sample>(
+    plane: &Plane<T>,
+    spec: &FilterSpec,
+    max_size: usize,
+    rows: Range<u32>,
+) -> Vec<T> {
+    let kernels = adaptive_kernels(&spec.kernel, max_size);
+    let mut values: Vec<T> = Vec::with_capacity(kernels.last().map_or(0, Kernel::len));
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
+
+    for y in rows {
+        for x in 0..plane.width {
+            let center = plane.get(x, y);
+            let mut result = center;
+
+            for kernel in &kernels {
+                values.clear();
+                values.extend(kernel.offsets().iter().filter_map(|&(dx, dy)| {
+                    plane.get_padded(x as i32 + dx, y as i32 + dy, spec.border)
+                }));
+                if values.is_empty() {
+                    continue;
+                }
+
+                let min = values.iter().copied().min_by(T::total_cmp).unwrap();
+                let max = values.iter().copied().max_by(T::total_cmp).unwrap();
+                let median = select_median(&mut values);
+                result = median;
+
+                // The median is no impulse: keep the pixel unless it is one
+                if min.total_cmp(&median).is_lt() && median.total_cmp(&max).is_lt() {
+                    if min.total_cmp(&center).is_lt() && center.total_cmp(&max).is_lt() {
+                        result = center;
+                    }
+                    break;
+                }
+            }
+
+            output.push(result);
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 9x9 plane of 100s with a 5x5 checkerboard of pepper (0) and salt
+    /// (255) in the middle; the center pixel is pepper
+    fn impulse_block() -> Plane {
+        let data = (0..81u32)
+            .map(|i| {
+                let (x, y) = (i % 9, i / 9);
+                match (x, y) {
+                    (2..=6, 2..=6) if (x + y) % 2 == 0 => 0,
+                    (2..=6, 2..=6) => 255,
+                    _ => 100,
+                }
+            })
+            .collect();
+        Plane { data, width: 9, height: 9 }
+    }
+
+    fn center(max_size: usize) -> u8 {
+        adaptive_rows(&impulse_block(), &FilterSpec::new(3), max_size, 4..5)[4]
+    }
+
+    /// The 3x3 and 5x5 medians at the center are pepper, the 7x7 one is not:
+    /// the window grows to 7x7, or stops at the largest allowed window
+    #[test]
+    fn window_grows_until_the_median_is_no_impulse() {
+        assert_eq!(center(7), 100);
+        assert_eq!(center(9), 100);
+        assert_eq!(center(5), 0);
+        assert_eq!(center(3), 0);
+    }
+
+    /// Every impulse of the block is replaced, and the 100s around it,
+    /// which are no impulses, keep their value
+    #[test]
+    fn all_impulses_are_replaced() {
+        let output = adaptive_rows(&impulse_block(), &FilterSpec::new(3), 7, 0..9);
+        assert!(output.iter().all(|&v| v == 100), "{:?}", output);
+    }
+}
@@ -0,0 +1,637 @@
+use crate::adaptive;
+use crate::color::ColorSpace;
+use crate::kernel::{Kernel, KernelShape};
+use crate::region::Region;
+use crate::sample::SampleType;
+use crate::shared::{
+    AlphaMode, AnyImage, Algorithm, BorderMode, ColorType, RankFilter, VectorNorm, MAX_KERNEL_SIZE,
+};
+use crate::switching::ImpulseDetector;
+use crate::{distributed, gpu, parallel, sequential, simd, vector};
+use std::fmt;
+
+/// Parameters of a median filter run, shared by all backends
+#[derive(Debug, Clone)]
+pub struct FilterSpec {
+    /// Footprint of the filter window
+    pub kernel: Kernel,
+    /// Treatment of pixels outside the image
+    pub border: BorderMode,
+    /// Median algorithm used by the CPU backends
+    pub algorithm: Algorithm,
+    /// Order statistic taken from each window: the median, or another rank
+    /// filter such as min, max or a percentile
+    pub rank: RankFilter,
+    /// Treatment of the alpha channel of images with transparency
+    pub alpha: AlphaMode,
+    /// Select whole pixels with the vector median under this norm instead
+    /// of filtering channels separately (CPU backends only)
+    pub vector: Option<VectorNorm>,
+    /// Replace only impulses, growing the window per pixel up to this size
+    /// (adaptive median, CPU backends only)
+    pub adaptive: Option<usize>,
+    /// Integer weight of each kernel pixel, in the order of
+    /// `kernel.offsets()`; a sample counts as many times as its weight
+    /// (weighted median). `None` weighs every pixel 1.
+    pub weights: Option<Vec<u32>>,
+    /// Color space the image is converted to for filtering
+    pub color_space: ColorSpace,
+    /// Color channels to filter, as indices into the color space's channels;
+    /// the others are copied unchanged. `None` filters every channel.
+    pub channels: Option<Vec<usize>>,
+    /// Pixels to filter; the others keep their input value. `None` filters
+    /// the whole image.
+    pub region: Option<Region>,
+    /// Replace only the pixels this detector marks as impulses (switching
+    /// median); `None` replaces every pixel
+    pub switching: Option<ImpulseDetector>,
+}
+
+impl FilterSpec {
+    /// Create a spec for a square kernel of the given size
+    pub fn new(kernel_size: usize) -> Self {
+        FilterSpec::from_kernel(Kernel::square(kernel_size))
+    }
+
+    /// Create a spec for an arbitrary kernel footprint
+    pub fn from_kernel(kernel: Kernel) -> Self {
+        FilterSpec {
+            kernel,
+            border: BorderMode::default(),
+            algorithm: Algorithm::default(),
+            rank: RankFilter::default(),
+            alpha: AlphaMode::default(),
+            vector: None,
+            adaptive: None,
+            weights: None,
+            color_space: ColorSpace::default(),
+            channels: None,
+            region: None,
+            switching: None,
+        }
+    }
+
+    /// Number of rows above and below a pixel the filter may read
+    pub fn reach(&self) -> usize {
+        self.largest_window().1 / 2
+    }
+
+    /// Number of columns left and right of a pixel the filter may read
+    pub fn horizontal_reach(&self) -> usize {
+        self.largest_window().0 / 2
+    }
+
+    /// Width and height of the largest window the filter uses: the kernel,
+    /// or the adaptive median's last window
+    fn largest_window(&self) -> (usize, usize) {
+        match self.adaptive {
+            Some(max_size) => {
+                let kernels = adaptive::adaptive_kernels(&self.kernel, max_size);
+                kernels.last().map_or((0, 0), |k| (k.width(), k.height()))
+            }
+            None => (self.kernel.width(), self.kernel.height()),
+        }
+    }
+
+    /// Whether channel `channel` of an image of `color` type is filtered
+    ///
+    /// Alpha is filtered only under `AlphaMode::Filter`, color channels only
+    /// if they are among `channels`.
+    pub fn filters_channel(&self, channel: usize, color: ColorType) -> bool {
+        if color.alpha_channel() == Some(channel) {
+            return self.alpha == AlphaMode::Filter;
+        }
+
+        match &self.channels {
+            Some(channels) => channels.contains(&channel),
+            None => true,
+        }
+    }
+
+    /// Algorithm the CPU backends use on images of `sample` type, resolving
+    /// `Auto`
+    ///
+    /// Opaque-only and weighted medians skip or repeat samples per pixel,
+    /// which only the sort algorithm supports.
+    pub fn resolve_algorithm(&self, sample: SampleType) -> Algorithm {
+        if self.weights.is_some() {
+            return Algorithm::Sort;
+        }
+
+        match self.alpha {
+            AlphaMode::Opaque(_) => Algorithm::Sort,
+            AlphaMode::Filter | AlphaMode::Keep => self.algorithm.for_kernel(&self.kernel, self.rank, sample),
+        }
+    }
+
+    /// `algorithm` prefixed with the rank filter unless it is the median
+    /// and suffixed with the impulse detector of a switching median, as
+    /// backends report it
+    pub fn reported_algorithm(&self, algorithm: String) -> String {
+        let algorithm = match self.rank {
+            RankFilter::Median => algorithm,
+            rank => format!("{}-{}", rank, algorithm),
+        };
+        match self.switching {
+            Some(detector) => format!("{}+switching-{}", algorithm, detector),
+            None => algorithm,
+        }
+    }
+
+    /// Check that the spec describes a filter a backend supporting kernels
+    /// up to `max_kernel_size` can run
+    pub fn validate(&self, max_kernel_size: usize) -> Result<(), FilterError> {
+        for size in [self.kernel.width(), self.kernel.height()] {
+            if size.is_multiple_of(2) {
+                return Err(FilterError::InvalidKernelSize(size));
+            }
+
+            if size > max_kernel_size {
+                return Err(FilterError::KernelTooLarge {
+                    size,
+                    max: max_kernel_size,
+                });
+            }
+        }
+
+        if !self.algorithm.supports(&self.kernel) {
+            let needs = match self.algorithm {
+                Algorithm::Simd => "a 3x3 or 5x5 square kernel",
+                _ => "a rectangular kernel",
+            };
+            return Err(FilterError::Unsupported(format!(
+                "the {} algorithm needs {}, got {}",
+                self.algorithm, needs, self.kernel
+            )));
+        }
+
+        if self.vector.is_some() && self.algorithm != Algorithm::Auto {
+            return Err(FilterError::Unsupported(format!(
+                "the vector median selects whole pixels and cannot use the {} algorithm",
+                self.algorithm
+            )));
+        }
+
+        let largest = self.kernel.width().max(self.kernel.height());
+        if self.vector.is_some() && largest > vector::MAX_KERNEL_SIZE {
+            return Err(FilterError::Unsupported(format!(
+                "the vector median compares every pair of window pixels and allows kernels up to {0}x{0}, got {1}",
+                vector::MAX_KERNEL_SIZE,
+                self.kernel
+            )));
+        }
+
+        if let Some(max_size) = self.adaptive {
+            self.validate_adaptive(max_size, max_kernel_size)?;
+        }
+
+        if let Some(weights) = &self.weights {
+            self.validate_weights(weights)?;
+        }
+
+        if self.rank != RankFilter::Median {
+            self.validate_rank()?;
+        }
+
+        if self.vector.is_some() && self.channels.is_some() {
+            return Err(FilterError::Unsupported(
+                "the vector median selects whole pixels and cannot filter a subset of channels"
+                    .to_string(),
+            ));
+        }
+
+        if matches!(self.alpha, AlphaMode::Opaque(_))
+            && !matches!(self.algorithm, Algorithm::Auto | Algorithm::Sort)
+        {
+            return Err(FilterError::Unsupported(format!(
+                "alpha mode {} needs the sort algorithm, got {}",
+                self.alpha, self.algorithm
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn validate_weights(&self, weights: &[u32]) -> Result<(), FilterError> {
+        let unsupported = if weights.len() != self.kernel.len() {
+            format!("{} weights given for the {} pixels of the {} kernel", weights.len(), self.kernel.len(), self.kernel)
+        } else if weights.iter().all(|&w| w == 0) {
+            "the weights of a weighted median must not all be zero".to_string()
+        } else if !matches!(self.algorithm, Algorithm::Auto | Algorithm::Sort) {
+            format!("weighted medians need the sort algorithm, got {}", self.algorithm)
+        } else if self.adaptive.is_some() {
+            "the adaptive median grows the window and cannot use weights".to_string()
+        } else {
+            return Ok(());
+        };
+
+        Err(FilterError::Unsupported(unsupported))
+    }
+
+    fn validate_rank(&self) -> Result<(), FilterError> {
+        let unsupported = if self.algorithm == Algorithm::Simd {
+            format!("the simd algorithm computes medians only, not {}", self.rank)
+        } else if self.vector.is_some() {
+            format!("the vector median selects whole pixels and has no {} filter", self.rank)
+        } else if self.adaptive.is_some() {
+            format!("the adaptive median tests window medians and cannot output {}", self.rank)
+        } else {
+            return Ok(());
+        };
+
+        Err(FilterError::Unsupported(unsupported))
+    }
+
+    fn validate_adaptive(&self, max_size: usize, max_kernel_size: usize) -> Result<(), FilterError> {
+        if max_size.is_multiple_of(2) {
+            return Err(FilterError::InvalidKernelSize(max_size));
+        }
+        if max_size > max_kernel_size {
+            return Err(FilterError::KernelTooLarge {
+                size: max_size,
+                max: max_kernel_size,
+            });
+        }
+
+        let unsupported = if max_size < self.kernel.width().max(self.kernel.height()) {
+            format!("the adaptive median's largest window {} is smaller than the {} kernel", max_size, self.kernel)
+        } else if self.kernel.shape() == KernelShape::Custom {
+            "the adaptive median grows the window's shape and cannot use a custom mask".to_string()
+        } else if self.algorithm != Algorithm::Auto {
+            format!("the adaptive median cannot use the {} algorithm", self.algorithm)
+        } else if self.vector.is_some() {
+            "the adaptive median filters channels separately and has no vector median".to_string()
+        } else if matches!(self.alpha, AlphaMode::Opaque(_)) {
+            format!("the adaptive median cannot use alpha mode {}", self.alpha)
+        } else {
+            return Ok(());
+        };
+
+        Err(FilterError::Unsupported(unsupported))
+    }
+
+    /// Check that the spec's algorithm can filter images of `sample` type
+    pub fn validate_samples(&self, sample: SampleType) -> Result<(), FilterError> {
+        if !self.algorithm.supports_samples(sample) {
+            return Err(FilterError::Unsupported(format!(
+                "the {} algorithm needs 8-bit samples, got {}",
+                self.algorithm, sample
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the spec's color space and channels apply to images of
+    /// `color` type
+    pub fn validate_color(&self, color: ColorType) -> Result<(), FilterError> {
+        if self.color_space != ColorSpace::Rgb && color.color_channels() != 3 {
+            return Err(FilterError::Unsupported(format!(
+                "the {} color space needs a color image, got {}",
+                self.color_space, color
+            )));
+        }
+
+        let mut channels = self.channels.iter().flatten();
+        if let Some(&channel) = channels.find(|&&c| c >= color.color_channels()) {
+            let name = match self.color_space.channel_names().get(channel) {
+                Some(name) => name.to_string(),
+                None => channel.to_string(),
+            };
+            return Err(FilterError::Unsupported(format!(
+                "{} images have no {} channel",
+                color, name
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors reported by median filter backends
+#[derive(Debug)]
+pub enum FilterError {
+    /// Kernel size is not an odd number
+    InvalidKernelSize(usize),
+    /// Kernel size exceeds the backend's upper bound
+    KernelTooLarge { size: usize, max: usize },
+    /// Custom kernel mask could not be loaded or is malformed
+    InvalidMask(String),
+    /// Region of interest could not be loaded or does not fit the image
+    InvalidRegion(String),
+    /// Combination of options the filter cannot run
+    Unsupported(String),
+    /// No backend registered under the given name
+    UnknownBackend(String),
+    /// GPU initialization or execution failed
+    Gpu(String),
+    /// MPI initialization or communication failed
+    Distributed(String),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::InvalidKernelSize(size) => {
+                write!(f, "Kernel size must be odd, got {}", size)
+            }
+            FilterError::KernelTooLarge { size, max } => {
+                write!(f, "Kernel size {} exceeds the maximum of {} for this method", size, max)
+            }
+            FilterError::InvalidMask(msg) => write!(f, "Invalid kernel mask: {}", msg),
+            FilterError::InvalidRegion(msg) => write!(f, "Invalid region: {}", msg),
+            FilterError::Unsupported(msg) => write!(f, "Unsupported filter: {}", msg),
+            FilterError::UnknownBackend(name) => {
+                let names: Vec<&str> = available_backends().iter().map(|b| b.name).collect();
+                write!(f, "Unknown method '{}'. Available: {}", name, names.join(", "))
+            }
+            FilterError::Gpu(msg) => write!(f, "GPU error: {}", msg),
+            FilterError::Distributed(msg) => write!(f, "MPI error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+/// Common interface of all median filter implementations
+pub trait MedianBackend {
+    /// Short name used to select the backend (e.g. "seq")
+    fn name(&self) -> &'static str;
+
+    /// Apply the filter described by `spec` to `img`
+    fn filter(&self, img: &AnyImage, spec: &FilterSpec) -> Result<AnyImage, FilterError>;
+
+    /// Name of the median algorithm the backend uses for `spec` on images
+    /// of `sample` type
+    ///
+    /// The SIMD algorithm is reported with the instruction set detected on
+    /// this CPU (e.g. "simd-avx2"), the vector median with its norm (e.g.
+    /// "vector-l1") and the adaptive median with its largest window (e.g.
+    /// "adaptive-7"). Weighted medians get a "weighted-" prefix, rank
+    /// filters other than the median their name (e.g. "percentile:90-sort")
+    /// and switching medians their detector (e.g. "sort+switching-extreme").
+    fn algorithm(&self, spec: &FilterSpec, sample: SampleType) -> String {
+        let algorithm = if let Some(norm) = spec.vector {
+            format!("vector-{}", norm)
+        } else if let Some(max_size) = spec.adaptive {
+            format!("adaptive-{}", max_size)
+        } else {
+            match spec.resolve_algorithm(sample) {
+                Algorithm::Simd => format!("simd-{}", simd::instruction_set()),
+                algorithm => algorithm.to_string(),
+            }
+        };
+
+        let algorithm = match spec.weights {
+            Some(_) => format!("weighted-{}", algorithm),
+            None => algorithm,
+        };
+        spec.reported_algorithm(algorithm)
+    }
+
+    /// Largest kernel size the backend accepts
+    fn max_kernel_size(&self) -> usize {
+        MAX_KERNEL_SIZE
+    }
+
+    /// Whether this process holds the filtered result
+    ///
+    /// Always true except on MPI worker ranks, whose `filter` output is a placeholder.
+    fn is_root(&self) -> bool {
+        true
+    }
+
+    /// Number of processes taking part in filtering
+    fn num_processes(&self) -> i32 {
+        1
+    }
+
+    /// Share the root's `flag` with every process and return it
+    ///
+    /// Lets all processes agree on a decision only the root can make, such as
+    /// when to stop filtering repeatedly. Single-process backends return
+    /// `flag` unchanged.
+    fn broadcast_flag(&self, flag: bool) -> bool {
+        flag
+    }
+}
+
+/// Registry entry describing a backend that can be created at runtime
+pub struct BackendInfo {
+    /// Name used to select the backend
+    pub name: &'static str,
+    /// Human-readable description
+    pub description: &'static str,
+    create: fn() -> Result<Box<dyn MedianBackend>, FilterError>,
+}
+
+impl BackendInfo {
+    /// Create an instance of the backend
+    pub fn create(&self) -> Result<Box<dyn MedianBackend>, FilterError> {
+        (self.create)()
+    }
+}
+
+static BACKENDS: &[BackendInfo] = &[
+    BackendInfo {
+        name: "seq",
+        description: "Sequential CPU implementation",
+        create: || Ok(Box::new(sequential::SequentialBackend)),
+    },
+    BackendInfo {
+        name: "par",
+        description: "Parallel CPU implementation (Rayon)",
+        create: || Ok(Box::new(parallel::ParallelBackend)),
+    },
+    BackendInfo {
+        name: "gpu",
+        description: "GPU implementation (WGPU/WGSL)",
+        create: || Ok(Box::new(gpu::GpuBackend)),
+    },
+    BackendInfo {
+        name: "dist",
+        description: "Distributed implementation (MPI)",
+        create: || Ok(Box::new(distributed::DistributedBackend::new()?)),
+    },
+];
+
+/// List all registered backends
+pub fn available_backends() -> &'static [BackendInfo] {
+    BACKENDS
+}
+
+/// Create the backend registered under `name`
+pub fn create_backend(name: &str) -> Result<Box<dyn MedianBackend>, FilterError> {
+    BACKENDS
+        .iter()
+        .find(|b| b.name == name)
+        .ok_or_else(|| FilterError::UnknownBackend(name.to_string()))?
+        .create()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::impulse_image;
+
+    fn test_image(width: u32, height: u32) -> AnyImage {
+        AnyImage::U8(impulse_image(width, height))
+    }
+
+    fn samples(img: &AnyImage) -> &[u8] {
+        match img {
+            AnyImage::U8(img) => &img.data,
+            _ => panic!("8-bit image expected"),
+        }
+    }
+
+    /// Filter with `name`, or `None` if it is the GPU backend and this
+    /// machine has no adapter
+    fn try_filter(name: &str, img: &AnyImage, spec: &FilterSpec) -> Option<AnyImage> {
+        match create_backend(name).unwrap().filter(img, spec) {
+            Ok(filtered) => Some(filtered),
+            Err(FilterError::Gpu(msg)) if msg.starts_with("Failed to find GPU adapter") => None,
+            Err(e) => panic!("{} backend failed: {}", name, e),
+        }
+    }
+
+    /// Check that every single-process backend filters the test images
+    /// exactly like the sequential one under `border`
+    ///
+    /// The MPI backend needs `mpirun` and is left out.
+    fn assert_backends_agree(border: BorderMode) {
+        for (width, height) in [(13, 9), (4, 3), (1, 6)] {
+            let img = test_image(width, height);
+            for kernel_size in [3, 5, 7] {
+                let mut spec = FilterSpec::new(kernel_size);
+                spec.border = border;
+                let expected = try_filter("seq", &img, &spec).unwrap();
+
+                for backend in available_backends().iter().filter(|b| b.name != "dist") {
+                    if let Some(filtered) = try_filter(backend.name, &img, &spec) {
+                        assert!(
+                            samples(&filtered) == samples(&expected),
+                            "{} differs from seq on {}x{} with {}x{} kernel and {} border",
+                            backend.name,
+                            width,
+                            height,
+                            kernel_size,
+                            kernel_size,
+                            border
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree_reflect101() {
+        assert_backends_agree(BorderMode::Reflect101);
+    }
+
+    #[test]
+    fn backends_agree_reflect() {
+        assert_backends_agree(BorderMode::Reflect);
+    }
+
+    #[test]
+    fn backends_agree_replicate() {
+        assert_backends_agree(BorderMode::Replicate);
+    }
+
+    #[test]
+    fn backends_agree_wrap() {
+        assert_backends_agree(BorderMode::Wrap);
+    }
+
+    #[test]
+    fn backends_agree_constant() {
+        assert_backends_agree(BorderMode::Constant(128));
+    }
+
+    #[test]
+    fn backends_agree_shrink() {
+        assert_backends_agree(BorderMode::Shrink);
+    }
+
+    /// Every algorithm a kernel supports gives the sort algorithm's result,
+    /// for the median and the other rank filters the algorithm accepts
+    #[test]
+    fn algorithms_agree() {
+        let img = test_image(17, 11);
+        let seq = create_backend("seq").unwrap();
+        let algorithms = [Algorithm::ConstantTime, Algorithm::Huang, Algorithm::Simd];
+        let ranks = [
+            RankFilter::Median,
+            RankFilter::Min,
+            RankFilter::Percentile(9000),
+            RankFilter::TrimmedMean(2500),
+        ];
+        let borders = [
+            BorderMode::Reflect101,
+            BorderMode::Wrap,
+            BorderMode::Constant(0),
+            BorderMode::Shrink,
+        ];
+        let kernels = [
+            Kernel::square(3),
+            Kernel::square(5),
+            Kernel::with_shape(KernelShape::Square, 7, 3),
+            Kernel::with_shape(KernelShape::Disk, 9, 9),
+        ];
+
+        for kernel in kernels {
+            for (rank, border) in ranks.iter().flat_map(|&r| borders.iter().map(move |&b| (r, b))) {
+                let mut spec = FilterSpec::from_kernel(kernel.clone());
+                spec.rank = rank;
+                spec.border = border;
+                spec.algorithm = Algorithm::Sort;
+                let expected = seq.filter(&img, &spec).unwrap();
+
+                for algorithm in algorithms {
+                    spec.algorithm = algorithm;
+                    if spec.validate(seq.max_kernel_size()).is_err() {
+                        continue;
+                    }
+                    let filtered = seq.filter(&img, &spec).unwrap();
+                    assert!(
+                        samples(&filtered) == samples(&expected),
+                        "{} differs from sort with {} kernel, {} rank and {} border",
+                        algorithm,
+                        kernel,
+                        rank,
+                        border
+                    );
+                }
+            }
+        }
+    }
+
+    /// A center weight of at least the window size outweighs all other
+    /// samples together, so every backend returns the input unchanged
+    #[test]
+    fn heavy_center_weight_keeps_the_center() {
+        let img = test_image(13, 9);
+        let kernels = [Kernel::square(3), Kernel::square(5), Kernel::with_shape(KernelShape::Disk, 7, 7)];
+
+        for kernel in kernels {
+            for weight in [kernel.len(), kernel.len() + 3] {
+                let mut spec = FilterSpec::from_kernel(kernel.clone());
+                spec.weights = Some(kernel.center_weights(weight as u32));
+
+                for backend in available_backends().iter().filter(|b| b.name != "dist") {
+                    if let Some(filtered) = try_filter(backend.name, &img, &spec) {
+                        assert!(
+                            samples(&filtered) == samples(&img),
+                            "{} changed pixels with {} kernel and center weight {}",
+                            backend.name,
+                            kernel,
+                            weight
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
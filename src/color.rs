@@ -0,0 +1,361 @@
+//! Color spaces the median filter can work in.
+//!
+//! Filtering in RGB treats the three channels alike. Converting to a space
+//! that separates luminance from chroma lets the filter denoise only one of
+//! them, e.g. the Y channel of YCbCr. Converted images keep the sample type of
+//! the original: every channel is scaled to the sample type's black to white
+//! range, so the backends filter them like any other image.
+
+use crate::backend::{FilterError, FilterSpec};
+use crate::sample::Sample;
+use crate::shared::{map_any_image, AnyImage, ColorType, Image};
+use std::fmt;
+use std::str::FromStr;
+
+/// Color space the median is applied in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// Channels of the image as stored
+    #[default]
+    Rgb,
+    /// Luma and blue/red chroma (BT.601, full range as in JPEG)
+    YCbCr,
+    /// Hue, saturation and value; hue is filtered as a plain number, so reds
+    /// on either side of 0° are not treated as neighbors
+    Hsv,
+    /// CIELAB lightness and green-red/blue-yellow axes (sRGB, D65 white)
+    Lab,
+}
+
+impl ColorSpace {
+    /// Names of the three color channels, as accepted by `parse_channels`
+    pub fn channel_names(self) -> [&'static str; 3] {
+        match self {
+            ColorSpace::Rgb => ["r", "g", "b"],
+            ColorSpace::YCbCr => ["y", "cb", "cr"],
+            ColorSpace::Hsv => ["h", "s", "v"],
+            ColorSpace::Lab => ["l", "a", "b"],
+        }
+    }
+
+    /// Parse a comma-separated list of channel names (e.g. "cb,cr") into
+    /// channel indices
+    pub fn parse_channels(self, list: &str) -> Result<Vec<usize>, String> {
+        let names = self.channel_names();
+        let mut channels = Vec::new();
+
+        for name in list.split(',').map(|name| name.trim().to_lowercase()) {
+            let channel = names.iter().position(|&n| n == name).ok_or_else(|| {
+                format!(
+                    "Unknown {} channel '{}', expected one of: {}",
+                    self,
+                    name,
+                    names.join(", ")
+                )
+            })?;
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+
+        Ok(channels)
+    }
+
+    /// Convert a pixel's RGB channels, scaled to 0.0..=1.0, to this space
+    fn encode(self, [r, g, b]: [f64; 3]) -> [f64; 3] {
+        match self {
+            ColorSpace::Rgb => [r, g, b],
+            ColorSpace::YCbCr => {
+                let y = 0.299 * r + 0.587 * g + 0.114 * b;
+                [y, 0.5 + (b - y) / 1.772, 0.5 + (r - y) / 1.402]
+            }
+            ColorSpace::Hsv => {
+                let max = r.max(g).max(b);
+                let delta = max - r.min(g).min(b);
+                let hue = if delta <= 0.0 {
+                    0.0
+                } else if max == r {
+                    ((g - b) / delta).rem_euclid(6.0)
+                } else if max == g {
+                    (b - r) / delta + 2.0
+                } else {
+                    (r - g) / delta + 4.0
+                };
+                let saturation = if max <= 0.0 { 0.0 } else { delta / max };
+                [hue / 6.0, saturation, max]
+            }
+            ColorSpace::Lab => {
+                let [r, g, b] = [r, g, b].map(srgb_to_linear);
+                let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / WHITE_X;
+                let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+                let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / WHITE_Z;
+                let [fx, fy, fz] = [x, y, z].map(lab_f);
+                let (l, a, b) = (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz));
+                [l / 100.0, (a + 128.0) / 255.0, (b + 128.0) / 255.0]
+            }
+        }
+    }
+
+    /// Inverse of `encode`
+    fn decode(self, [c0, c1, c2]: [f64; 3]) -> [f64; 3] {
+        match self {
+            ColorSpace::Rgb => [c0, c1, c2],
+            ColorSpace::YCbCr => {
+                let r = c0 + 1.402 * (c2 - 0.5);
+                let b = c0 + 1.772 * (c1 - 0.5);
+                let g = (c0 - 0.299 * r - 0.114 * b) / 0.587;
+                [r, g, b]
+            }
+            ColorSpace::Hsv => {
+                let (hue, saturation, value) = ((c0 * 6.0).rem_euclid(6.0), c1, c2);
+                let chroma = value * saturation;
+                let x = chroma * (1.0 - ((hue % 2.0) - 1.0).abs());
+                let m = value - chroma;
+                let [r, g, b] = match hue as u32 {
+                    0 => [chroma, x, 0.0],
+                    1 => [x, chroma, 0.0],
+                    2 => [0.0, chroma, x],
+                    3 => [0.0, x, chroma],
+                    4 => [x, 0.0, chroma],
+                    _ => [chroma, 0.0, x],
+                };
+                [r + m, g + m, b + m]
+            }
+            ColorSpace::Lab => {
+                let (l, a, b) = (c0 * 100.0, c1 * 255.0 - 128.0, c2 * 255.0 - 128.0);
+                let fy = (l + 16.0) / 116.0;
+                let [x, y, z] = [fy + a / 500.0, fy, fy - b / 200.0].map(lab_f_inverse);
+                let (x, z) = (x * WHITE_X, z * WHITE_Z);
+                [
+                    3.2404548 * x - 1.5371389 * y - 0.4985315 * z,
+                    -0.9692664 * x + 1.8760109 * y + 0.0415561 * z,
+                    0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+                ]
+                .map(linear_to_srgb)
+            }
+        }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rgb" => Ok(ColorSpace::Rgb),
+            "ycbcr" => Ok(ColorSpace::YCbCr),
+            "hsv" => Ok(ColorSpace::Hsv),
+            "lab" => Ok(ColorSpace::Lab),
+            other => Err(format!(
+                "Unknown color space '{}', expected one of: rgb, ycbcr, hsv, lab",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorSpace::Rgb => write!(f, "rgb"),
+            ColorSpace::YCbCr => write!(f, "ycbcr"),
+            ColorSpace::Hsv => write!(f, "hsv"),
+            ColorSpace::Lab => write!(f, "lab"),
+        }
+    }
+}
+
+/// D65 reference white, relative to its Y (the row sums of the RGB to XYZ
+/// matrix)
+const WHITE_X: f64 = 0.95047;
+const WHITE_Z: f64 = 1.08883;
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn lab_f(t: f64) -> f64 {
+    const DELTA: f64 = 6.0 / 29.0;
+    if t > DELTA * DELTA * DELTA {
+        t.cbrt()
+    } else {
+        t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+    }
+}
+
+fn lab_f_inverse(t: f64) -> f64 {
+    const DELTA: f64 = 6.0 / 29.0;
+    if t > DELTA {
+        t * t * t
+    } else {
+        3.0 * DELTA * DELTA * (t - 4.0 / 29.0)
+    }
+}
+
+/// Color channels of a pixel scaled to 0.0..=1.0
+fn scaled<T: Sample>(pixel: &[T]) -> [f64; 3] {
+    let max = T::MAX.to_f64();
+    [pixel[0].to_f64() / max, pixel[1].to_f64() / max, pixel[2].to_f64() / max]
+}
+
+fn unscaled<T: Sample>(color: [f64; 3]) -> [T; 3] {
+    let max = T::MAX.to_f64();
+    color.map(|c| T::from_f64(c * max))
+}
+
+/// Convert an RGB(A) image to `space`; alpha is copied
+pub fn convert_to<T: Sample>(img: &Image<T>, space: ColorSpace) -> Image<T> {
+    let mut converted = img.clone();
+    for pixel in converted.data.chunks_exact_mut(img.channels()) {
+        let color = unscaled::<T>(space.encode(scaled(pixel)));
+        pixel[..3].copy_from_slice(&color);
+    }
+    converted
+}
+
+/// Convert the filtered `filtered` image from `space` back to RGB(A)
+///
+/// `converted` is the filter's input, `convert_to(original, space)`. Pixels
+/// whose color the filter left unchanged get their `original` color back
+/// exactly, so rounding in the conversion only affects filtered pixels.
+/// Alpha is taken from `filtered`.
+pub fn convert_back<T: Sample>(
+    filtered: &Image<T>,
+    converted: &Image<T>,
+    original: &Image<T>,
+    space: ColorSpace,
+) -> Image<T> {
+    let channels = original.channels();
+    let mut output = filtered.clone();
+    let pixels = output
+        .data
+        .chunks_exact_mut(channels)
+        .zip(converted.data.chunks_exact(channels))
+        .zip(original.data.chunks_exact(channels));
+
+    for ((pixel, before), rgb) in pixels {
+        if pixel[..3] == before[..3] {
+            pixel[..3].copy_from_slice(&rgb[..3]);
+        } else {
+            let color = unscaled::<T>(space.decode(scaled(pixel)));
+            pixel[..3].copy_from_slice(&color);
+        }
+    }
+
+    output
+}
+
+/// Run `filter` on `img` converted to the spec's color space and convert the
+/// result back
+///
+/// Images are passed through unchanged in the RGB color space. Other color
+/// spaces need an RGB(A) image.
+pub fn filter_in_color_space<F>(img: &AnyImage, spec: &FilterSpec, filter: F) -> Result<AnyImage, FilterError>
+where
+    F: FnOnce(&AnyImage) -> Result<AnyImage, FilterError>,
+{
+    let space = spec.color_space;
+    if space == ColorSpace::Rgb {
+        return filter(img);
+    }
+
+    let converted = map_any_image!(img, img => convert_to(img, space));
+    let filtered = filter(&converted)?;
+    Ok(match (&filtered, &converted, img) {
+        (AnyImage::U8(f), AnyImage::U8(c), AnyImage::U8(o)) => AnyImage::U8(convert_back(f, c, o, space)),
+        (AnyImage::U16(f), AnyImage::U16(c), AnyImage::U16(o)) => AnyImage::U16(convert_back(f, c, o, space)),
+        (AnyImage::F32(f), AnyImage::F32(c), AnyImage::F32(o)) => AnyImage::F32(convert_back(f, c, o, space)),
+        _ => unreachable!("filtering keeps the sample type"),
+    })
+}
+
+/// Luma (BT.601) of every pixel as a single-channel image; grayscale images
+/// just drop their alpha channel
+pub fn luminance<T: Sample>(img: &Image<T>) -> Image<T> {
+    let luma = |pixel: &[T]| match img.color {
+        ColorType::Gray | ColorType::GrayAlpha => pixel[0],
+        ColorType::Rgb | ColorType::Rgba => {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(T::to_f64);
+            T::from_f64(0.299 * r + 0.587 * g + 0.114 * b)
+        }
+    };
+
+    Image {
+        data: img.data.chunks_exact(img.channels()).map(luma).collect(),
+        width: img.width,
+        height: img.height,
+        color: ColorType::Gray,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::impulse_image;
+
+    const SPACES: [ColorSpace; 3] = [ColorSpace::YCbCr, ColorSpace::Hsv, ColorSpace::Lab];
+
+    /// Largest channel difference between `pixel` and its color converted to
+    /// `space`, stored in `T` and decoded back
+    fn round_trip_error<T: Sample>(space: ColorSpace, pixel: [T; 3]) -> f64 {
+        let img = Image { data: pixel.to_vec(), width: 1, height: 1, color: ColorType::Rgb };
+        let converted = convert_to(&img, space);
+        let back = unscaled::<T>(space.decode(scaled(&converted.data)));
+        (0..3).map(|c| (back[c].to_f64() - pixel[c].to_f64()).abs()).fold(0.0, f64::max)
+    }
+
+    /// Every combination of `levels` evenly spaced values per channel,
+    /// black and white included
+    fn grid<T: Sample>(levels: u32) -> Vec<[T; 3]> {
+        let value = |i: u32| T::from_f64(T::MAX.to_f64() * i as f64 / (levels - 1) as f64);
+        let mut pixels = Vec::new();
+        for r in 0..levels {
+            for g in 0..levels {
+                for b in 0..levels {
+                    pixels.push([value(r), value(g), value(b)]);
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Integer YCbCr keeps colors to within one quantization step; floats
+    /// keep every space to within the f32 rounding of the channels
+    #[test]
+    fn round_trip_within_one_step() {
+        for pixel in grid::<u8>(52) {
+            assert!(round_trip_error(ColorSpace::YCbCr, pixel) <= 1.0, "{:?}", pixel);
+        }
+        for pixel in grid::<u16>(52) {
+            assert!(round_trip_error(ColorSpace::YCbCr, pixel) <= 1.0, "{:?}", pixel);
+        }
+        for space in SPACES {
+            for pixel in grid::<f32>(52) {
+                assert!(round_trip_error(space, pixel) <= 2e-6, "{:?} in {}", pixel, space);
+            }
+        }
+    }
+
+    /// HSV and Lab lose more than a step in 8 bits (hue of grays, sRGB near
+    /// black), so pixels the filter leaves alone get their original back
+    #[test]
+    fn unfiltered_pixels_are_restored_exactly() {
+        let img = impulse_image(9, 7);
+        for space in SPACES {
+            let converted = convert_to(&img, space);
+            assert_eq!(convert_back(&converted, &converted, &img, space).data, img.data, "{}", space);
+        }
+    }
+}
@@ -1,33 +1,156 @@
-use crate::shared::{collect_neighborhood, median_rgb, Image};
-use image::Rgb;
+use crate::backend::{FilterError, FilterSpec, MedianBackend};
+use crate::color;
+use crate::region;
+use crate::sample::Sample;
+use crate::shared::{filter_image_rows, map_any_image, noise_rng, AnyImage, BorderMode, ColorType, Image, Plane};
+use crate::switching;
+use mpi::environment::Universe;
+use mpi::topology::SimpleCommunicator;
 use mpi::traits::*;
 use std::path::Path;
 
+/// Apply median filter using MPI, splitting the image into row stripes
+///
+/// Rank 0 loads the image, adds noise drawn from `seed` and distributes the
+/// stripes (with ghost rows) to the workers, then assembles the filtered image.
+///
+/// # Returns
+/// Filtered image (only meaningful on rank 0), the rank of the calling
+/// process and the number of processes
 pub fn apply_median_filter_mpi(
     input_path: &Path,
     noise_level: f32,
+    seed: u64,
     kernel_size: usize,
-) -> (Image, i32, i32) {
+) -> (AnyImage, i32, i32) {
     let universe = mpi::initialize().expect("Failed to initialize MPI");
     let world = universe.world();
     let rank = world.rank();
     let size = world.size();
 
-    let half_kernel = (kernel_size / 2) as i32;
-
-    // Root process loads image and broadcasts dimensions
-    if rank == 0 {
-        let mut img = Image::load(input_path).expect("Failed to load image");
+    let img = if rank == 0 {
+        let mut img = AnyImage::load(input_path).expect("Failed to load image");
         if noise_level > 0.0 {
-            crate::shared::add_noise(&mut img, noise_level);
+            img.add_noise(noise_level, &mut noise_rng(seed));
+        }
+        img
+    } else {
+        AnyImage::U8(Image::new_empty(1, 1))
+    };
+
+    let output = filter_with_world(&world, &img, &FilterSpec::new(kernel_size));
+    (output, rank, size)
+}
+
+/// Distributed (MPI) median filter backend
+///
+/// Holds the MPI environment for the lifetime of the backend, so it can be
+/// created only once per process. Every rank must call `filter`; only the
+/// image passed on rank 0 is used.
+pub struct DistributedBackend {
+    universe: Universe,
+}
+
+impl DistributedBackend {
+    /// Initialize MPI and create the backend
+    pub fn new() -> Result<Self, FilterError> {
+        let universe = mpi::initialize().ok_or_else(|| {
+            FilterError::Distributed("Failed to initialize MPI".to_string())
+        })?;
+        Ok(DistributedBackend { universe })
+    }
+}
+
+impl MedianBackend for DistributedBackend {
+    fn name(&self) -> &'static str {
+        "dist"
+    }
+
+    fn filter(&self, img: &AnyImage, spec: &FilterSpec) -> Result<AnyImage, FilterError> {
+        spec.validate(self.max_kernel_size())?;
+        let world = self.universe.world();
+
+        // Only the root holds the image, so it checks what depends on it and
+        // shares the outcome before any point-to-point traffic; otherwise
+        // workers would wait for data the root never sends
+        let checked = if world.rank() == 0 { validate_image(img, spec) } else { Ok(()) };
+        if !self.broadcast_flag(checked.is_ok()) {
+            checked?;
+            return Err(FilterError::Distributed(
+                "the root process rejected the filter for its image".to_string(),
+            ));
+        }
+
+        if world.rank() != 0 {
+            // Workers hold a placeholder image; the root detects impulses,
+            // crops the region and converts color spaces
+            return Ok(filter_with_world(&world, img, spec));
+        }
+
+        switching::filter_switched(img, spec, |img, spec| {
+            region::filter_in_region(img, spec, |img| {
+                color::filter_in_color_space(img, spec, |img| Ok(filter_with_world(&world, img, spec)))
+            })
+        })
+    }
+
+    fn is_root(&self) -> bool {
+        self.universe.world().rank() == 0
+    }
+
+    fn num_processes(&self) -> i32 {
+        self.universe.world().size()
+    }
+
+    fn broadcast_flag(&self, flag: bool) -> bool {
+        let mut flag = flag;
+        self.universe.world().process_at_rank(0).broadcast_into(&mut flag);
+        flag
+    }
+}
+
+/// Checks of `spec` against the image that `FilterSpec::validate` cannot
+/// make without it
+fn validate_image(img: &AnyImage, spec: &FilterSpec) -> Result<(), FilterError> {
+    spec.validate_samples(img.sample_type())?;
+    spec.validate_color(img.color())?;
+    match &spec.region {
+        Some(region) => region.validate(img.width(), img.height()),
+        None => Ok(()),
+    }
+}
+
+fn filter_with_world(world: &SimpleCommunicator, img: &AnyImage, spec: &FilterSpec) -> AnyImage {
+    if world.rank() == 0 {
+        // Workers learn the sample type from its bit depth
+        for dest in 1..world.size() {
+            world.process_at_rank(dest).send(&img.sample_type().bits());
+        }
+        map_any_image!(img, img => filter_typed(world, img, spec))
+    } else {
+        let bits: u32 = world.process_at_rank(0).receive().0;
+        match bits {
+            8 => AnyImage::U8(filter_typed(world, &Image::new_empty(1, 1), spec)),
+            16 => AnyImage::U16(filter_typed(world, &Image::new_empty(1, 1), spec)),
+            _ => AnyImage::F32(filter_typed(world, &Image::new_empty(1, 1), spec)),
         }
+    }
+}
 
+/// Filter `img` (rank 0) or the chunk received from rank 0 (workers)
+fn filter_typed<T: Sample>(world: &SimpleCommunicator, img: &Image<T>, spec: &FilterSpec) -> Image<T> {
+    let rank = world.rank();
+    let size = world.size();
 
+    let half_kernel = spec.reach() as i32;
 
+    // Root process distributes the image and broadcasts dimensions
+    if rank == 0 {
         // Broadcast dimensions to all processes
         for dest in 1..size {
             world.process_at_rank(dest).send(&img.width);
             world.process_at_rank(dest).send(&img.height);
+            world.process_at_rank(dest).send(&(img.channels() as u32));
         }
 
         // Distribute work
@@ -42,14 +165,19 @@ pub fn apply_median_filter_mpi(
                 continue;
             }
 
-            // Calculate ghost region
-            let ghost_start = (start_row - half_kernel).max(0);
-            let ghost_end = (end_row + half_kernel).min(img.height as i32);
+            // Calculate ghost region. Ghost rows beyond the image edge are padded
+            // like the full image would be, so every chunk sees the same
+            // neighbors; with shrink the chunk simply ends at the edge.
+            let (ghost_start, ghost_end) = if spec.border == BorderMode::Shrink {
+                ((start_row - half_kernel).max(0), (end_row + half_kernel).min(img.height as i32))
+            } else {
+                (start_row - half_kernel, end_row + half_kernel)
+            };
+            let chunk = extract_chunk(img, ghost_start, ghost_end, spec.border);
 
             if proc == 0 {
                 // Process 0 processes its own chunk
-                let chunk = extract_chunk(&img, ghost_start, ghost_end);
-                let processed = process_chunk(&chunk, start_row - ghost_start, end_row - ghost_start, kernel_size);
+                let processed = process_chunk(&chunk, start_row - ghost_start, end_row - ghost_start, spec);
                 results.push((start_row, end_row, processed));
             } else {
                 // Send chunk to worker process
@@ -58,8 +186,8 @@ pub fn apply_median_filter_mpi(
                 world.process_at_rank(proc).send(&ghost_start);
                 world.process_at_rank(proc).send(&ghost_end);
 
-                let chunk_data = serialize_chunk(&img, ghost_start, ghost_end);
-                world.process_at_rank(proc).send(&chunk_data[..]);
+                let chunk_data = serialize_chunk(&chunk, 0, chunk.height as i32);
+                world.process_at_rank(proc).send(bytemuck::cast_slice::<T, u8>(&chunk_data));
             }
         }
 
@@ -73,29 +201,28 @@ pub fn apply_median_filter_mpi(
             }
 
             let chunk_height = (end_row - start_row) as usize;
-            let mut buffer = vec![0u8; img.width as usize * chunk_height * 3];
-            world.process_at_rank(proc).receive_into(&mut buffer[..]);
+            let mut buffer = vec![T::default(); img.width as usize * chunk_height * img.channels()];
+            world.process_at_rank(proc).receive_into(bytemuck::cast_slice_mut::<T, u8>(&mut buffer));
 
-            let processed = deserialize_chunk(&buffer, img.width, chunk_height as u32);
+            let processed = deserialize_chunk(buffer, img.width, chunk_height as u32, img.color);
             results.push((start_row, end_row, processed));
         }
 
         // Assemble final image
-        let mut output = Image::new_empty(img.width, img.height);
-        for (start_row, end_row, chunk) in results {
-            for y in start_row..end_row {
-                for x in 0..img.width {
-                    let pixel = chunk.get_pixel(x, (y - start_row) as u32);
-                    output.put_pixel(x, y as u32, *pixel);
-                }
-            }
+        let mut output = Image::new(img.width, img.height, img.color);
+        let row_len = img.width as usize * img.channels();
+        for (start_row, _end_row, chunk) in results {
+            let offset = start_row as usize * row_len;
+            output.data[offset..offset + chunk.data.len()].copy_from_slice(&chunk.data);
         }
 
-        (output, rank, size)
+        output
     } else {
         // Worker process
         let width: u32 = world.process_at_rank(0).receive().0;
         let _height: u32 = world.process_at_rank(0).receive().0;
+        let channels: u32 = world.process_at_rank(0).receive().0;
+        let color = ColorType::from_channels(channels as usize).expect("root sends a valid channel count");
 
         let start_row: i32 = world.process_at_rank(0).receive().0;
         let end_row: i32 = world.process_at_rank(0).receive().0;
@@ -103,71 +230,68 @@ pub fn apply_median_filter_mpi(
         let ghost_end: i32 = world.process_at_rank(0).receive().0;
 
         let ghost_height = (ghost_end - ghost_start) as usize;
-        let mut chunk_data = vec![0u8; width as usize * ghost_height * 3];
-        world.process_at_rank(0).receive_into(&mut chunk_data[..]);
+        let mut chunk_data = vec![T::default(); width as usize * ghost_height * color.channels()];
+        world.process_at_rank(0).receive_into(bytemuck::cast_slice_mut::<T, u8>(&mut chunk_data));
 
-        let chunk = deserialize_chunk(&chunk_data, width, ghost_height as u32);
-        let processed = process_chunk(&chunk, start_row - ghost_start, end_row - ghost_start, kernel_size);
+        let chunk = deserialize_chunk(chunk_data, width, ghost_height as u32, color);
+        let processed = process_chunk(&chunk, start_row - ghost_start, end_row - ghost_start, spec);
 
         // Send result back
         let result_data = serialize_chunk(&processed, 0, processed.height as i32);
-        world.process_at_rank(0).send(&result_data[..]);
+        world.process_at_rank(0).send(bytemuck::cast_slice::<T, u8>(&result_data));
 
-        (Image::new_empty(1, 1), rank, size)
+        Image::new_empty(1, 1)
     }
 }
 
-fn extract_chunk(img: &Image, start_row: i32, end_row: i32) -> Image {
+/// Copy rows `start_row..end_row` of `img`, padding rows outside the image
+/// according to `border`
+fn extract_chunk<T: Sample>(img: &Image<T>, start_row: i32, end_row: i32, border: BorderMode) -> Image<T> {
     let height = (end_row - start_row) as u32;
-    let mut chunk = Image::new_empty(img.width, height);
-
-    for y in start_row..end_row {
-        for x in 0..img.width {
-            let pixel = img.get_pixel(x, y as u32);
-            chunk.put_pixel(x, (y - start_row) as u32, *pixel);
+    let mut chunk = Image::new(img.width, height, img.color);
+    let row_len = img.width as usize * img.channels();
+
+    for (y, row) in (start_row..end_row).zip(chunk.data.chunks_exact_mut(row_len)) {
+        match border.resolve(y, img.height) {
+            Some(src) => {
+                let offset = src as usize * row_len;
+                row.copy_from_slice(&img.data[offset..offset + row_len]);
+            }
+            None => match border {
+                BorderMode::Constant(value) => row.fill(T::from_u8(value)),
+                _ => unreachable!("shrink ghost rows stay inside the image"),
+            },
         }
     }
 
     chunk
 }
 
-fn serialize_chunk(img: &Image, start_row: i32, end_row: i32) -> Vec<u8> {
-    let mut data = Vec::new();
-    for y in start_row..end_row {
-        for x in 0..img.width {
-            let pixel = img.get_pixel(x, y as u32);
-            data.push(pixel[0]);
-            data.push(pixel[1]);
-            data.push(pixel[2]);
-        }
-    }
-    data
+fn serialize_chunk<T: Sample>(img: &Image<T>, start_row: i32, end_row: i32) -> Vec<T> {
+    let row_len = img.width as usize * img.channels();
+    img.data[start_row as usize * row_len..end_row as usize * row_len].to_vec()
 }
 
-fn deserialize_chunk(data: &[u8], width: u32, height: u32) -> Image {
-    let mut chunk = Image::new_empty(width, height);
-    for y in 0..height {
-        for x in 0..width {
-            let idx = ((y * width + x) * 3) as usize;
-            let pixel = Rgb([data[idx], data[idx + 1], data[idx + 2]]);
-            chunk.put_pixel(x, y, pixel);
-        }
+fn deserialize_chunk<T: Sample>(data: Vec<T>, width: u32, height: u32, color: ColorType) -> Image<T> {
+    debug_assert_eq!(data.len(), width as usize * height as usize * color.channels());
+    Image {
+        data,
+        width,
+        height,
+        color,
     }
-    chunk
 }
 
-fn process_chunk(chunk: &Image, start_row: i32, end_row: i32, kernel_size: usize) -> Image {
-    let output_height = (end_row - start_row) as u32;
-    let mut output = Image::new_empty(chunk.width, output_height);
-
-    for y in start_row..end_row {
-        for x in 0..chunk.width {
-            let neighborhood = collect_neighborhood(chunk, x, y as u32, kernel_size);
-            let median_pixel = median_rgb(&neighborhood);
-            output.put_pixel(x, (y - start_row) as u32, median_pixel);
-        }
-    }
-
-    output
+fn process_chunk<T: Sample>(chunk: &Image<T>, start_row: i32, end_row: i32, spec: &FilterSpec) -> Image<T> {
+    let planes: Vec<Plane<T>> = (0..chunk.channels()).map(|channel| chunk.plane(channel)).collect();
+    let output = filter_image_rows(&planes, chunk.color, spec, start_row as u32..end_row as u32)
+        .into_iter()
+        .map(|data| Plane {
+            data,
+            width: chunk.width,
+            height: (end_row - start_row) as u32,
+        })
+        .collect();
+
+    Image::from_planes(output, chunk.color)
 }
-
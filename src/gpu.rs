@@ -1,66 +1,299 @@
-use crate::shared::Image;
-use image::Rgb;
+use crate::backend::{FilterError, FilterSpec, MedianBackend};
+use crate::color;
+use crate::region;
+use crate::sample::{Sample, SampleType};
+use crate::shared::{map_any_image, AlphaMode, Algorithm, AnyImage, BorderMode, Image, RankFilter};
+use crate::switching;
 use wgpu::util::DeviceExt;
 
+/// Largest kernel size supported on the GPU
+///
+/// The shader sorts windows up to 5x5 and selects the median of larger ones
+/// with 256-bin histograms, one pass per byte of the sample, so the window
+/// size only affects the cost of gathering samples. Larger windows risk
+/// driver watchdog timeouts on big images.
+pub const MAX_KERNEL_SIZE: usize = 63;
+
 const SHADER_SOURCE: &str = r#"
 struct Params {
     width: u32,
     height: u32,
-    kernel_size: u32,
+    num_offsets: u32,
+    border_mode: u32,
+    border_value: u32,
+    channels: u32,
+    bits: u32,
+    alpha_mode: u32,
+    alpha_threshold: u32,
+    // Bit c set if channel c is filtered, otherwise it is copied
+    channel_mask: u32,
+    rank_mode: u32,
+    // Rank in basis points for RANK_SELECT, trim fraction for RANK_TRIMMED_MEAN
+    rank_value: u32,
 }
 
+const BORDER_REFLECT101: u32 = 0u;
+const BORDER_REFLECT: u32 = 1u;
+const BORDER_REPLICATE: u32 = 2u;
+const BORDER_WRAP: u32 = 3u;
+const BORDER_CONSTANT: u32 = 4u;
+
+// Alpha modes (see shared::AlphaMode); images without alpha use ALPHA_FILTER
+const ALPHA_FILTER: u32 = 0u;
+const ALPHA_KEEP: u32 = 1u;
+const ALPHA_OPAQUE: u32 = 2u;
+
+// Rank filters (see shared::RankFilter): the sample at a percentile (the
+// median, min and max included) or the mean between two percentiles
+const RANK_SELECT: u32 = 0u;
+const RANK_TRIMMED_MEAN: u32 = 1u;
+
 @group(0) @binding(0) var<storage, read> input: array<u32>;
 @group(0) @binding(1) var<storage, read_write> output: array<u32>;
 @group(0) @binding(2) var<uniform> params: Params;
+@group(0) @binding(3) var<storage, read> offsets: array<vec2<i32>>;
+// Number of times each offset's sample counts (weighted median)
+@group(0) @binding(4) var<storage, read> weights: array<u32>;
+
+// Non-negative remainder of a / b for b > 0. Works on magnitudes because
+// some drivers evaluate signed % with negative operands as unsigned.
+fn rem_euclid(a: i32, b: i32) -> i32 {
+    let r = i32(u32(abs(a)) % u32(b));
+    return select(r, (b - r) % b, a < 0);
+}
+
+// Map a coordinate into 0..size according to the border mode (see
+// shared::BorderMode::resolve); -1 when there is no image pixel to use
+fn resolve(coord: i32, size: i32) -> i32 {
+    if (coord >= 0 && coord < size) {
+        return coord;
+    }
+
+    let mode = params.border_mode;
+    if (mode == BORDER_REFLECT101) {
+        if (size == 1) {
+            return 0;
+        }
+        let period = 2 * (size - 1);
+        let m = rem_euclid(coord, period);
+        return select(period - m, m, m < size);
+    } else if (mode == BORDER_REFLECT) {
+        let period = 2 * size;
+        let m = rem_euclid(coord, period);
+        return select(period - 1 - m, m, m < size);
+    } else if (mode == BORDER_REPLICATE) {
+        return clamp(coord, 0, size - 1);
+    } else if (mode == BORDER_WRAP) {
+        return rem_euclid(coord, size);
+    }
+    return -1;
+}
+
+// Samples per u32 word and words per pixel; each pixel starts a new word
+fn samples_per_word() -> u32 {
+    return 32u / params.bits;
+}
+
+fn words_per_pixel() -> u32 {
+    return (params.channels + samples_per_word() - 1u) / samples_per_word();
+}
+
+// Samples are ordering keys (see Sample::to_key) of `bits` bits, packed
+// into the words of their pixel from the least significant bits up
+fn get_pixel_channel(x: u32, y: u32, channel: u32) -> u32 {
+    let pixel_idx = y * params.width + x;
+    let word = input[pixel_idx * words_per_pixel() + channel / samples_per_word()];
+    if (params.bits == 32u) {
+        return word;
+    }
+    let shift = (channel % samples_per_word()) * params.bits;
+    return (word >> shift) & ((1u << params.bits) - 1u);
+}
+
+// Whether a pixel with this alpha key takes part in color medians
+fn is_opaque(alpha: u32) -> bool {
+    return params.alpha_mode != ALPHA_OPAQUE || alpha >= params.alpha_threshold;
+}
+
+// Key of the window sample at `offset` from (x, y), or false when the
+// border mode or the alpha mode skips it
+fn window_key(x: i32, y: i32, offset: vec2<i32>, channel: u32, key: ptr<function, u32>) -> bool {
+    let px = resolve(x + offset.x, i32(params.width));
+    let py = resolve(y + offset.y, i32(params.height));
+
+    if (px < 0 || py < 0) {
+        // Outside the image: constant padding counts, shrink skips
+        *key = params.border_value;
+        return params.border_mode == BORDER_CONSTANT && is_opaque(params.border_value);
+    }
+
+    *key = get_pixel_channel(u32(px), u32(py), channel);
+    return is_opaque(get_pixel_channel(u32(px), u32(py), params.channels - 1u));
+}
+
+// Total weight of the window samples of one channel at (x, y)
+fn window_count(x: i32, y: i32, channel: u32) -> u32 {
+    var n = 0u;
+    var key = 0u;
+    for (var i = 0u; i < params.num_offsets; i++) {
+        if (window_key(x, y, offsets[i], channel, &key)) {
+            n += weights[i];
+        }
+    }
+    return n;
+}
 
-fn median9(values: array<u32, 9>) -> u32 {
-    var v = values;
-    for (var i = 0u; i < 9u; i++) {
-        for (var j = i + 1u; j < 9u; j++) {
-            if (v[i] > v[j]) {
-                let tmp = v[i];
-                v[i] = v[j];
-                v[j] = tmp;
+// (count * basis_points) / 10000 plus `round` / 10000, without overflowing
+// for any window size
+fn basis_points_of(count: u32, basis_points: u32, round: u32) -> u32 {
+    return (count / 10000u) * basis_points + ((count % 10000u) * basis_points + round) / 10000u;
+}
+
+// Footprints of at most this many offsets (5x5) are sorted, not histogrammed
+const SMALL_WINDOW: u32 = 25u;
+
+// Key at `index` of the sorted window samples of one channel
+//
+// Small windows are sorted in registers. Larger ones use radix selection:
+// each pass counts one byte of the keys, most significant first, in a
+// histogram restricted to keys matching the bytes chosen so far, then walks
+// it up to the bin holding that index. Samples count with their offset's
+// weight.
+fn select_key(x: i32, y: i32, channel: u32, index: u32) -> u32 {
+    if (params.num_offsets <= SMALL_WINDOW) {
+        return select_small(x, y, channel, index);
+    }
+
+    var hist: array<u32, 256>;
+    var key = 0u;
+
+    // Rank of the index among the keys still matching `prefix`
+    var rank = index;
+    var prefix = 0u;
+    var mask = 0u;
+    for (var shift = i32(params.bits) - 8; shift >= 0; shift -= 8) {
+        for (var b = 0u; b < 256u; b++) {
+            hist[b] = 0u;
+        }
+        for (var i = 0u; i < params.num_offsets; i++) {
+            if (window_key(x, y, offsets[i], channel, &key) && (key & mask) == prefix) {
+                hist[(key >> u32(shift)) & 0xFFu] += weights[i];
+            }
+        }
+
+        var bin = 0u;
+        while (rank >= hist[bin]) {
+            rank -= hist[bin];
+            bin++;
+        }
+        prefix |= bin << u32(shift);
+        mask |= 0xFFu << u32(shift);
+    }
+    return prefix;
+}
+
+// select_key for footprints of at most SMALL_WINDOW offsets: insertion sort
+// of the window keys with their weights, then a walk up to the index. For
+// 3x3 and 5x5 windows this is cheaper than clearing and scanning 256 bins
+// per byte of the sample.
+fn select_small(x: i32, y: i32, channel: u32, index: u32) -> u32 {
+    var keys: array<u32, 25>;
+    var counts: array<u32, 25>;
+    var n = 0u;
+    var key = 0u;
+    for (var i = 0u; i < params.num_offsets; i++) {
+        if (window_key(x, y, offsets[i], channel, &key) && weights[i] > 0u) {
+            var j = n;
+            while (j > 0u && keys[j - 1u] > key) {
+                keys[j] = keys[j - 1u];
+                counts[j] = counts[j - 1u];
+                j--;
             }
+            keys[j] = key;
+            counts[j] = weights[i];
+            n++;
         }
     }
-    return v[4];
+
+    var rank = index;
+    var j = 0u;
+    while (rank >= counts[j]) {
+        rank -= counts[j];
+        j++;
+    }
+    return keys[j];
+}
+
+// Float sample of a 32-bit key, inverse of Sample::to_key for f32
+fn key_to_float(key: u32) -> f32 {
+    return bitcast<f32>(select(~key, key & 0x7FFFFFFFu, (key >> 31u) == 1u));
 }
 
-fn median25(values: array<u32, 25>) -> u32 {
-    var v = values;
-    for (var i = 0u; i < 25u; i++) {
-        for (var j = i + 1u; j < 25u; j++) {
-            if (v[i] > v[j]) {
-                let tmp = v[i];
-                v[i] = v[j];
-                v[j] = tmp;
+fn float_to_key(value: f32) -> u32 {
+    let bits = bitcast<u32>(value);
+    return select(bits | 0x80000000u, ~bits, (bits >> 31u) == 1u);
+}
+
+// Mean of the `n` window samples without the `trim` smallest and largest
+//
+// The samples at both ends of the kept range are found by radix selection;
+// everything strictly between them is summed, and their copies fill the
+// remaining ranks. Integer sums fit in u32 because the backend rejects
+// weights whose total times the largest key does not; float sums use f32
+// and may differ from the CPU's f64 sum in the last bit.
+fn trimmed_mean(x: i32, y: i32, channel: u32, n: u32, trim: u32) -> u32 {
+    let low = select_key(x, y, channel, trim);
+    let high = select_key(x, y, channel, n - 1u - trim);
+    if (low == high) {
+        return low;
+    }
+
+    var at_most_low = 0u;
+    var below_high = 0u;
+    var sum = 0u;
+    var float_sum = 0.0;
+    var key = 0u;
+    for (var i = 0u; i < params.num_offsets; i++) {
+        if (window_key(x, y, offsets[i], channel, &key)) {
+            let weight = weights[i];
+            if (key <= low) {
+                at_most_low += weight;
+            }
+            if (key < high) {
+                below_high += weight;
+            }
+            if (key > low && key < high) {
+                sum += weight * key;
+                float_sum += f32(weight) * key_to_float(key);
             }
         }
     }
-    return v[12];
+
+    let low_copies = at_most_low - trim;
+    let high_copies = n - trim - below_high;
+    let kept = n - 2u * trim;
+    if (params.bits == 32u) {
+        float_sum += f32(low_copies) * key_to_float(low) + f32(high_copies) * key_to_float(high);
+        return float_to_key(float_sum / f32(kept));
+    }
+    sum += low_copies * low + high_copies * high;
+    return (sum + kept / 2u) / kept;
 }
 
-fn get_pixel_channel(x: i32, y: i32, channel: u32) -> u32 {
-    // Mirror padding logic
-    var nx = x;
-    var ny = y;
-    
-    if (nx < 0) { nx = -nx; }
-    if (nx >= i32(params.width)) { nx = 2 * i32(params.width) - nx - 2; }
-    if (ny < 0) { ny = -ny; }
-    if (ny >= i32(params.height)) { ny = 2 * i32(params.height) - ny - 2; }
-
-    let pixel_idx = u32(ny) * params.width + u32(nx);
-    let packed = input[pixel_idx];
-
-    if (channel == 0u) {
-        return (packed >> 16u) & 0xFFu;
-    } else if (channel == 1u) {
-        return (packed >> 8u) & 0xFFu;
-    } else {
-        return packed & 0xFFu;
+// Rank filter of one channel over the kernel footprint centered at (x, y)
+fn channel_value(x: i32, y: i32, channel: u32) -> u32 {
+    let n = window_count(x, y, channel);
+
+    // Nothing left under the footprint: keep the center pixel, like the CPU version
+    if (n == 0u) {
+        return get_pixel_channel(u32(x), u32(y), channel);
+    }
+
+    if (params.rank_mode == RANK_TRIMMED_MEAN) {
+        let trim = min(basis_points_of(n, params.rank_value, 0u), (n - 1u) / 2u);
+        return trimmed_mean(x, y, channel, n, trim);
     }
+    return select_key(x, y, channel, basis_points_of(n - 1u, params.rank_value, 5000u));
 }
 
 @compute @workgroup_size(8, 8)
@@ -72,51 +305,116 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         return;
     }
 
-    let half = i32(params.kernel_size) / 2;
-    var result_packed = 0u;
-
-    // Process each channel separately
-    for (var channel = 0u; channel < 3u; channel++) {
-        var median_val = 0u;
-
-        if (params.kernel_size == 3u) {
-            var values: array<u32, 9>;
-            var idx = 0u;
-            for (var dy = -half; dy <= half; dy++) {
-                for (var dx = -half; dx <= half; dx++) {
-                    values[idx] = get_pixel_channel(i32(x) + dx, i32(y) + dy, channel);
-                    idx++;
-                }
-            }
-            median_val = median9(values);
-        } else { // kernel_size == 5
-            var values: array<u32, 25>;
-            var idx = 0u;
-            for (var dy = -half; dy <= half; dy++) {
-                for (var dx = -half; dx <= half; dx++) {
-                    values[idx] = get_pixel_channel(i32(x) + dx, i32(y) + dy, channel);
-                    idx++;
-                }
-            }
-            median_val = median25(values);
+    // Process each channel separately, copying the ones not filtered, and
+    // write the pixel's words once they are complete
+    let out_idx = (y * params.width + x) * words_per_pixel();
+    var word = 0u;
+    for (var channel = 0u; channel < params.channels; channel++) {
+        var key = 0u;
+        if (((params.channel_mask >> channel) & 1u) == 0u) {
+            key = get_pixel_channel(x, y, channel);
+        } else {
+            key = channel_value(i32(x), i32(y), channel);
         }
 
-        // Pack result
-        if (channel == 0u) {
-            result_packed |= (median_val << 16u);
-        } else if (channel == 1u) {
-            result_packed |= (median_val << 8u);
-        } else {
-            result_packed |= median_val;
+        let slot = channel % samples_per_word();
+        word |= key << (slot * params.bits);
+        if (slot == samples_per_word() - 1u || channel == params.channels - 1u) {
+            output[out_idx + channel / samples_per_word()] = word;
+            word = 0u;
         }
     }
-    
-    let out_idx = y * params.width + x;
-    output[out_idx] = result_packed;
 }
 "#;
 
-pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
+/// Apply median filter on the GPU using a WGSL compute shader
+///
+/// # Arguments
+/// * `img` - Input image
+/// * `kernel_size` - Size of the kernel (odd, at most `MAX_KERNEL_SIZE`)
+///
+/// # Returns
+/// Filtered image
+pub fn apply_median_filter<T: Sample>(img: &Image<T>, kernel_size: usize) -> Image<T> {
+    run_median_filter(img, &FilterSpec::new(kernel_size)).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// GPU median filter backend
+pub struct GpuBackend;
+
+impl MedianBackend for GpuBackend {
+    fn name(&self) -> &'static str {
+        "gpu"
+    }
+
+    fn filter(&self, img: &AnyImage, spec: &FilterSpec) -> Result<AnyImage, FilterError> {
+        spec.validate(self.max_kernel_size())?;
+        if spec.algorithm != Algorithm::Auto {
+            return Err(FilterError::Unsupported(format!(
+                "the GPU backend always uses its histogram shader, not {}",
+                spec.algorithm
+            )));
+        }
+        if spec.vector.is_some() {
+            return Err(FilterError::Unsupported(
+                "the GPU backend filters channels separately and has no vector median".to_string(),
+            ));
+        }
+        if spec.adaptive.is_some() {
+            return Err(FilterError::Unsupported(
+                "the GPU backend uses a fixed window and has no adaptive median".to_string(),
+            ));
+        }
+        spec.validate_color(img.color())?;
+        validate_weight_total(spec, img.sample_type())?;
+        switching::filter_switched(img, spec, |img, spec| {
+            region::filter_in_region(img, spec, |img| {
+                color::filter_in_color_space(img, spec, |img| {
+                    Ok(map_any_image!(img, img => run_median_filter(img, spec)?))
+                })
+            })
+        })
+    }
+
+    fn algorithm(&self, spec: &FilterSpec, _sample: SampleType) -> String {
+        let algorithm = match spec.weights {
+            Some(_) => "gpu-weighted-histogram",
+            None => "gpu-histogram",
+        };
+        spec.reported_algorithm(algorithm.to_string())
+    }
+
+    fn max_kernel_size(&self) -> usize {
+        MAX_KERNEL_SIZE
+    }
+}
+
+/// Check that the shader's u32 window totals cannot overflow for `spec`
+/// on images of `sample` type
+///
+/// Window counts add up the weights, and the integer trimmed mean sums
+/// weight times key; the CPU backends count in u64 and have no such limit.
+fn validate_weight_total(spec: &FilterSpec, sample: SampleType) -> Result<(), FilterError> {
+    let total: u64 = match &spec.weights {
+        Some(weights) => weights.iter().map(|&w| w as u64).sum(),
+        None => spec.kernel.len() as u64,
+    };
+    let max_key = match (spec.rank, sample) {
+        (RankFilter::TrimmedMean(_), SampleType::U8 | SampleType::U16) => (1 << sample.bits()) - 1,
+        _ => 1,
+    };
+
+    if total * max_key > u32::MAX as u64 {
+        return Err(FilterError::Unsupported(format!(
+            "the GPU backend counts window weights in 32 bits; a total weight of {} is too large{}",
+            total,
+            if max_key > 1 { format!(" for the trimmed mean of {} images", sample) } else { String::new() }
+        )));
+    }
+    Ok(())
+}
+
+fn run_median_filter<T: Sample>(img: &Image<T>, spec: &FilterSpec) -> Result<Image<T>, FilterError> {
     // Initialize WGPU
     let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
         backends: wgpu::Backends::all(),
@@ -128,17 +426,25 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
         compatible_surface: None,
         force_fallback_adapter: false,
     }))
-    .expect("Failed to find GPU adapter");
-
+    .map_err(|e| FilterError::Gpu(format!("Failed to find GPU adapter: {}", e)))?;
+
+    // Ask for the largest storage buffers the adapter allows; the defaults
+    // (128 MiB) are too small for large float images
+    let adapter_limits = adapter.limits();
+    let required_limits = wgpu::Limits {
+        max_buffer_size: adapter_limits.max_buffer_size,
+        max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+        ..wgpu::Limits::default()
+    };
     let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
         label: None,
         required_features: wgpu::Features::empty(),
-        required_limits: wgpu::Limits::default(),
+        required_limits,
         memory_hints: wgpu::MemoryHints::default(),
         trace: wgpu::Trace::Off,
         experimental_features: wgpu::ExperimentalFeatures::disabled(),
     }))
-    .expect("Failed to create device");
+    .map_err(|e| FilterError::Gpu(format!("Failed to create device: {}", e)))?;
 
     // Compile shader
     let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -146,17 +452,22 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
         source: wgpu::ShaderSource::Wgsl(SHADER_SOURCE.into()),
     });
 
-    // Pack RGB pixels into u32 (R << 16 | G << 8 | B)
-    let input_data: Vec<u32> = img
-        .data
-        .pixels()
-        .map(|p| {
-            let r = p[0] as u32;
-            let g = p[1] as u32;
-            let b = p[2] as u32;
-            (r << 16) | (g << 8) | b
-        })
-        .collect();
+    // Ordering keys of the samples, so the shader handles every sample type
+    // with the same unsigned comparisons, packed as tightly as the key size
+    // allows
+    let input_data = pack_keys(img);
+    let buffer_size = (input_data.len() * std::mem::size_of::<u32>()) as u64;
+    let max_binding_size = device.limits().max_storage_buffer_binding_size as u64;
+    if buffer_size > max_binding_size {
+        return Err(FilterError::Gpu(format!(
+            "a {}x{} {} image needs {} MiB of GPU storage, more than the {} MiB this device can bind",
+            img.width,
+            img.height,
+            T::TYPE,
+            buffer_size >> 20,
+            max_binding_size >> 20
+        )));
+    }
 
     let input_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Input Buffer"),
@@ -166,18 +477,72 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
 
     let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Output Buffer"),
-        size: (img.width * img.height * 4) as u64,
+        size: buffer_size,
         usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
         mapped_at_creation: false,
     });
 
-    let params = [img.width, img.height, kernel_size as u32];
+    let offsets = spec.kernel.offsets();
+    let (border_mode, border_value) = match spec.border {
+        BorderMode::Reflect101 => (0, 0),
+        BorderMode::Reflect => (1, 0),
+        BorderMode::Replicate => (2, 0),
+        BorderMode::Wrap => (3, 0),
+        BorderMode::Constant(value) => (4, T::from_u8(value).to_key()),
+        BorderMode::Shrink => (5, 0),
+    };
+    let (alpha_mode, alpha_threshold) = match (img.color.alpha_channel(), spec.alpha) {
+        (None, _) | (Some(_), AlphaMode::Filter) => (0, 0),
+        (Some(_), AlphaMode::Keep) => (1, 0),
+        (Some(_), AlphaMode::Opaque(threshold)) => (2, T::from_u8(threshold).to_key()),
+    };
+    let (rank_mode, rank_value) = match spec.rank {
+        RankFilter::Median => (0, 5000),
+        RankFilter::Min => (0, 0),
+        RankFilter::Max => (0, 10000),
+        RankFilter::Percentile(basis_points) => (0, basis_points),
+        RankFilter::TrimmedMean(basis_points) => (1, basis_points),
+    };
+    let params = [
+        img.width,
+        img.height,
+        offsets.len() as u32,
+        border_mode,
+        border_value,
+        img.channels() as u32,
+        T::TYPE.bits(),
+        alpha_mode,
+        alpha_threshold,
+        (0..img.channels())
+            .filter(|&channel| spec.filters_channel(channel, img.color))
+            .fold(0, |mask, channel| mask | 1 << channel),
+        rank_mode,
+        rank_value,
+    ];
     let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Params Buffer"),
         contents: bytemuck::cast_slice(&params),
         usage: wgpu::BufferUsages::UNIFORM,
     });
 
+    // Kernel footprint as (dx, dy) pairs
+    let offset_data: Vec<i32> = offsets.iter().flat_map(|&(dx, dy)| [dx, dy]).collect();
+    let offsets_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Offsets Buffer"),
+        contents: bytemuck::cast_slice(&offset_data),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+
+    let weight_data = match &spec.weights {
+        Some(weights) => weights.clone(),
+        None => vec![1; offsets.len()],
+    };
+    let weights_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Weights Buffer"),
+        contents: bytemuck::cast_slice(&weight_data),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+
     // Create bind group layout
     let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         label: Some("Bind Group Layout"),
@@ -212,6 +577,26 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
                 },
                 count: None,
             },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
         ],
     });
 
@@ -231,6 +616,14 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
                 binding: 2,
                 resource: params_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: offsets_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: weights_buffer.as_entire_binding(),
+            },
         ],
     });
 
@@ -252,7 +645,7 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
     // Create staging buffer for reading results
     let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Staging Buffer"),
-        size: (img.width * img.height * 4) as u64,
+        size: buffer_size,
         usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
         mapped_at_creation: false,
     });
@@ -281,7 +674,7 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
         0,
         &staging_buffer,
         0,
-        (img.width * img.height * 4) as u64,
+        buffer_size,
     );
 
     let submission_index = queue.submit(Some(encoder.finish()));
@@ -297,25 +690,51 @@ pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
         submission_index: Some(submission_index),
         timeout: None,
     });
-    receiver.recv().unwrap().expect("Failed to map buffer");
+    receiver
+        .recv()
+        .map_err(|e| FilterError::Gpu(format!("Failed to map buffer: {}", e)))?
+        .map_err(|e| FilterError::Gpu(format!("Failed to map buffer: {}", e)))?;
 
     let data = buffer_slice.get_mapped_range();
     let output_data: Vec<u32> = bytemuck::cast_slice(&data).to_vec();
     drop(data);
     staging_buffer.unmap();
 
-    // Unpack u32 back to RGB
-    let mut output = Image::new_empty(img.width, img.height);
-    for (i, &packed) in output_data.iter().enumerate() {
-        let x = (i as u32) % img.width;
-        let y = (i as u32) / img.width;
-
-        let r = ((packed >> 16) & 0xFF) as u8;
-        let g = ((packed >> 8) & 0xFF) as u8;
-        let b = (packed & 0xFF) as u8;
+    Ok(Image {
+        data: unpack_keys(&output_data, img.channels()),
+        width: img.width,
+        height: img.height,
+        color: img.color,
+    })
+}
 
-        output.put_pixel(x, y, Rgb([r, g, b]));
-    }
+/// Ordering keys of the samples of `img`, packed `32 / bits` to a u32 word
+/// from the least significant bits up; every pixel starts a new word
+///
+/// An RGB or RGBA 8-bit pixel fits one word, a 16-bit one two.
+fn pack_keys<T: Sample>(img: &Image<T>) -> Vec<u32> {
+    let bits = T::TYPE.bits();
+    let per_word = (32 / bits) as usize;
+    img.data
+        .chunks_exact(img.channels())
+        .flat_map(|pixel| pixel.chunks(per_word))
+        .map(|samples| {
+            samples
+                .iter()
+                .enumerate()
+                .fold(0, |word, (i, &v)| word | v.to_key() << (i as u32 * bits))
+        })
+        .collect()
+}
 
-    output
+/// Samples of the words `pack_keys` produces for pixels of `channels` channels
+fn unpack_keys<T: Sample>(words: &[u32], channels: usize) -> Vec<T> {
+    let bits = T::TYPE.bits();
+    let per_word = (32 / bits) as usize;
+    let mask = u32::MAX >> (32 - bits);
+    words
+        .chunks_exact(channels.div_ceil(per_word))
+        .flat_map(|pixel| (0..channels).map(move |c| pixel[c / per_word] >> ((c % per_word) as u32 * bits)))
+        .map(|key| T::from_key(key & mask))
+        .collect()
 }
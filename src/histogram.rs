@@ -0,0 +1,371 @@
+//! Histogram-based median algorithms for 8-bit images.
+//!
+//! Both algorithms return the same values as the sort-based median in
+//! `shared::median`, and the same as `selection::select_rank` for the other
+//! rank filters.
+
+use crate::kernel::Kernel;
+use crate::selection::trimmed_mean;
+use crate::shared::{BorderMode, Plane, RankFilter};
+use std::ops::Range;
+
+const BINS: usize = 256;
+const COARSE_BINS: usize = 16;
+
+/// Histogram of one channel of a single kernel column
+///
+/// Counts are small (at most the kernel height), so `u16` keeps the
+/// per-column state of wide images compact.
+#[derive(Clone, Copy)]
+struct ColumnHistogram {
+    coarse: [u16; COARSE_BINS],
+    fine: [u16; BINS],
+    count: u16,
+}
+
+impl ColumnHistogram {
+    const EMPTY: ColumnHistogram = ColumnHistogram {
+        coarse: [0; COARSE_BINS],
+        fine: [0; BINS],
+        count: 0,
+    };
+
+    fn add(&mut self, value: u8) {
+        self.coarse[value as usize >> 4] += 1;
+        self.fine[value as usize] += 1;
+        self.count += 1;
+    }
+
+    fn remove(&mut self, value: u8) {
+        self.coarse[value as usize >> 4] -= 1;
+        self.fine[value as usize] -= 1;
+        self.count -= 1;
+    }
+}
+
+/// Histogram of one channel over the whole kernel window, built from the
+/// column histograms `columns[position..position + span]`
+///
+/// The coarse bins follow the window at every step. The fine bins are only
+/// updated when a lookup needs them (the lazy update of Perreault & Hébert):
+/// each group of 16 fine bins remembers the position it was last brought up
+/// to date at and catches up by the columns that entered and left the
+/// window since, or is rebuilt from the window's columns when that is
+/// cheaper. Neighboring windows mostly find their median in the same group,
+/// so a step usually touches 16 coarse and 16 fine bins per column instead
+/// of all 256.
+struct KernelHistogram<'a> {
+    columns: &'a [ColumnHistogram],
+    span: usize,
+    position: usize,
+    coarse: [u32; COARSE_BINS],
+    fine: [u32; BINS],
+    updated: [Option<usize>; COARSE_BINS],
+    count: u32,
+}
+
+impl<'a> KernelHistogram<'a> {
+    /// Histogram of the window covering the first `span` columns
+    fn new(columns: &'a [ColumnHistogram], span: usize) -> Self {
+        let mut window = KernelHistogram {
+            columns,
+            span,
+            position: 0,
+            coarse: [0; COARSE_BINS],
+            fine: [0; BINS],
+            updated: [None; COARSE_BINS],
+            count: 0,
+        };
+        for column in &columns[..span] {
+            window.add_coarse(column);
+        }
+        window
+    }
+
+    /// Move the window one column to the right
+    fn shift(&mut self) {
+        let (leaving, entering) = (&self.columns[self.position], &self.columns[self.position + self.span]);
+        self.remove_coarse(leaving);
+        self.add_coarse(entering);
+        self.position += 1;
+    }
+
+    fn add_coarse(&mut self, column: &ColumnHistogram) {
+        for (bin, &n) in self.coarse.iter_mut().zip(column.coarse.iter()) {
+            *bin += n as u32;
+        }
+        self.count += column.count as u32;
+    }
+
+    fn remove_coarse(&mut self, column: &ColumnHistogram) {
+        for (bin, &n) in self.coarse.iter_mut().zip(column.coarse.iter()) {
+            *bin -= n as u32;
+        }
+        self.count -= column.count as u32;
+    }
+
+    /// Bring the fine bins of coarse bin `coarse` up to the window position
+    fn update_fine(&mut self, coarse: usize) {
+        const GROUP: usize = BINS / COARSE_BINS;
+        let bins = coarse * GROUP..(coarse + 1) * GROUP;
+        let fine = &mut self.fine[bins.clone()];
+
+        match self.updated[coarse] {
+            Some(updated) if 2 * (self.position - updated) < self.span => {
+                for x in updated..self.position {
+                    let (leaving, entering) = (&self.columns[x], &self.columns[x + self.span]);
+                    for ((bin, &out), &entered) in fine
+                        .iter_mut()
+                        .zip(&leaving.fine[bins.clone()])
+                        .zip(&entering.fine[bins.clone()])
+                    {
+                        *bin = *bin - out as u32 + entered as u32;
+                    }
+                }
+            }
+            _ => {
+                fine.fill(0);
+                for column in &self.columns[self.position..self.position + self.span] {
+                    for (bin, &n) in fine.iter_mut().zip(&column.fine[bins.clone()]) {
+                        *bin += n as u32;
+                    }
+                }
+            }
+        }
+
+        self.updated[coarse] = Some(self.position);
+    }
+
+    /// Value `filter` outputs for the window samples
+    fn value(&mut self, filter: RankFilter) -> u8 {
+        let count = self.count as u64;
+        match filter.rank(count) {
+            Some(rank) => self.select(rank as u32),
+            None => {
+                for coarse in 0..COARSE_BINS {
+                    self.update_fine(coarse);
+                }
+                let runs = self.fine.iter().enumerate().map(|(value, &n)| (value as u8, n as u64));
+                trimmed_mean(runs, count, filter.trimmed(count))
+            }
+        }
+    }
+
+    /// Value at index `target` of the sorted samples
+    fn select(&mut self, target: u32) -> u8 {
+        let mut seen = 0;
+
+        // Find the coarse bin holding the target, then scan its 16 fine bins
+        let mut coarse = 0;
+        while seen + self.coarse[coarse] <= target {
+            seen += self.coarse[coarse];
+            coarse += 1;
+        }
+
+        self.update_fine(coarse);
+        let mut value = coarse * (BINS / COARSE_BINS);
+        while seen + self.fine[value] <= target {
+            seen += self.fine[value];
+            value += 1;
+        }
+
+        value as u8
+    }
+}
+
+/// Median-filter rows `rows` of `plane` in constant time per pixel
+/// (Perreault & Hébert, 2007)
+///
+/// Keeps one histogram per image column covering the kernel height, slid
+/// down one row at a time, and builds the kernel histogram by adding the
+/// column entering the window and removing the one leaving it, updating its
+/// fine bins lazily. The cost per pixel is independent of the kernel size,
+/// except for the trimmed mean, which scans the whole histogram. `kernel`
+/// must be full.
+pub fn constant_time_rows(
+    plane: &Plane,
+    kernel: &Kernel,
+    filter: RankFilter,
+    border: BorderMode,
+    rows: Range<u32>,
+) -> Vec<u8> {
+    debug_assert!(kernel.is_full(), "constant-time median needs a rectangular kernel");
+
+    let rx = (kernel.width() / 2) as i32;
+    let ry = (kernel.height() / 2) as i32;
+    let width = plane.width as i32;
+
+    // Column i covers image column i - rx; columns outside the image are
+    // padded like every other out-of-bounds pixel
+    let mut columns = vec![ColumnHistogram::EMPTY; (width + 2 * rx) as usize];
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
+
+    let update = |columns: &mut [ColumnHistogram], y: i32, add: bool| {
+        for (i, column) in columns.iter_mut().enumerate() {
+            // Shrink skips pixels outside the image, so they never enter a column
+            if let Some(value) = plane.get_padded(i as i32 - rx, y, border) {
+                if add {
+                    column.add(value);
+                } else {
+                    column.remove(value);
+                }
+            }
+        }
+    };
+
+    for y in rows.clone() {
+        let y = y as i32;
+        if y == rows.start as i32 {
+            for dy in -ry..=ry {
+                update(&mut columns, y + dy, true);
+            }
+        } else {
+            update(&mut columns, y - ry - 1, false);
+            update(&mut columns, y + ry, true);
+        }
+
+        let mut window = KernelHistogram::new(&columns, 2 * rx as usize + 1);
+        for x in 0..width {
+            if x > 0 {
+                window.shift();
+            }
+            output.push(window.value(filter));
+        }
+    }
+
+    output
+}
+
+/// Histogram of one channel with a running median (Huang, 1979)
+///
+/// `below` counts the samples smaller than `position`; after every update
+/// the position moves bin by bin until `below` reaches the target rank
+/// (the middle of the samples for the median), which takes a few steps when
+/// neighboring windows are similar.
+#[derive(Clone, Copy)]
+struct RunningHistogram {
+    bins: [u32; BINS],
+    count: u32,
+    position: usize,
+    below: u32,
+}
+
+impl RunningHistogram {
+    const EMPTY: RunningHistogram = RunningHistogram {
+        bins: [0; BINS],
+        count: 0,
+        position: 0,
+        below: 0,
+    };
+
+    fn add(&mut self, value: u8) {
+        self.bins[value as usize] += 1;
+        self.count += 1;
+        if (value as usize) < self.position {
+            self.below += 1;
+        }
+    }
+
+    fn remove(&mut self, value: u8) {
+        self.bins[value as usize] -= 1;
+        self.count -= 1;
+        if (value as usize) < self.position {
+            self.below -= 1;
+        }
+    }
+
+    /// Value `filter` outputs for the window samples
+    ///
+    /// The trimmed mean has no single rank to track and scans the histogram.
+    fn value(&mut self, filter: RankFilter) -> u8 {
+        let count = self.count as u64;
+        match filter.rank(count) {
+            Some(rank) => self.select(rank as u32),
+            None => {
+                let runs = self.bins.iter().enumerate().map(|(value, &n)| (value as u8, n as u64));
+                trimmed_mean(runs, count, filter.trimmed(count))
+            }
+        }
+    }
+
+    /// Value at index `target` of the sorted samples
+    fn select(&mut self, target: u32) -> u8 {
+        while self.below > target {
+            self.position -= 1;
+            self.below -= self.bins[self.position];
+        }
+        while self.below + self.bins[self.position] <= target {
+            self.below += self.bins[self.position];
+            self.position += 1;
+        }
+        self.position as u8
+    }
+}
+
+/// Median-filter rows `rows` of `plane` with Huang's sliding histogram
+///
+/// Each row starts from a full window histogram; moving one pixel to the
+/// right removes the samples on the window's left edge and adds those on its
+/// new right edge, so the cost per pixel grows with the kernel height only.
+/// Works with any kernel shape.
+pub fn huang_rows(
+    plane: &Plane,
+    kernel: &Kernel,
+    filter: RankFilter,
+    border: BorderMode,
+    rows: Range<u32>,
+) -> Vec<u8> {
+    let offsets = kernel.offsets();
+    let contains = |dx: i32, dy: i32| offsets.contains(&(dx, dy));
+
+    // Offsets whose pixel enters the window when it moves right (evaluated at
+    // the new position) and leaves it (evaluated at the old position)
+    let entering: Vec<(i32, i32)> = offsets
+        .iter()
+        .copied()
+        .filter(|&(dx, dy)| !contains(dx + 1, dy))
+        .collect();
+    let leaving: Vec<(i32, i32)> = offsets
+        .iter()
+        .copied()
+        .filter(|&(dx, dy)| !contains(dx - 1, dy))
+        .collect();
+
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
+
+    for y in rows {
+        let y = y as i32;
+        let mut window = RunningHistogram::EMPTY;
+
+        let update = |window: &mut RunningHistogram, x: i32, offsets: &[(i32, i32)], add: bool| {
+            for &(dx, dy) in offsets {
+                if let Some(value) = plane.get_padded(x + dx, y + dy, border) {
+                    if add {
+                        window.add(value);
+                    } else {
+                        window.remove(value);
+                    }
+                }
+            }
+        };
+
+        update(&mut window, 0, offsets, true);
+
+        for x in 0..plane.width as i32 {
+            if x > 0 {
+                update(&mut window, x - 1, &leaving, false);
+                update(&mut window, x, &entering, true);
+            }
+
+            // Shrink may leave a custom mask without samples near the border
+            let value = if window.count == 0 {
+                plane.get(x as u32, y as u32)
+            } else {
+                window.value(filter)
+            };
+            output.push(value);
+        }
+    }
+
+    output
+}
@@ -0,0 +1,148 @@
+//! Repeated filtering.
+//!
+//! One median pass leaves impulses in place where they cluster more densely
+//! than the window can outvote. Filtering the output again removes more of
+//! them; after a few passes the image reaches a root signal that the filter
+//! no longer changes.
+
+use crate::backend::{FilterError, FilterSpec, MedianBackend};
+use crate::shared::AnyImage;
+use std::time::Instant;
+
+/// When repeated filtering stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repetition {
+    /// Largest number of passes
+    pub max_passes: usize,
+    /// Stop once fewer than this many pixels change in a pass; `None` runs
+    /// all `max_passes` passes
+    pub stable_threshold: Option<usize>,
+}
+
+impl Repetition {
+    /// Exactly `passes` passes
+    pub fn fixed(passes: usize) -> Self {
+        Repetition { max_passes: passes, stable_threshold: None }
+    }
+
+    /// Up to `max_passes` passes, stopping once fewer than `threshold`
+    /// pixels change in a pass
+    pub fn until_stable(max_passes: usize, threshold: usize) -> Self {
+        Repetition { max_passes, stable_threshold: Some(threshold) }
+    }
+}
+
+/// One finished pass, as reported to the caller of `filter_repeatedly`
+pub struct Pass<'a> {
+    /// Number of the pass, starting at 1
+    pub number: usize,
+    /// Image the pass filtered
+    pub input: &'a AnyImage,
+    /// Image the pass produced
+    pub output: &'a AnyImage,
+    /// Number of pixels the pass changed
+    pub changed: usize,
+}
+
+/// Result of `filter_repeatedly`
+pub struct Repeated {
+    /// Output of the last pass
+    pub image: AnyImage,
+    /// Number of passes run
+    pub passes: usize,
+    /// Whether the run stopped because a pass changed fewer pixels than the
+    /// stable threshold
+    pub stable: bool,
+    /// Time spent in the backend over all passes, in milliseconds
+    pub processing_time_ms: f64,
+}
+
+/// Filter `img` with `backend` repeatedly, every pass starting from the
+/// previous output
+///
+/// `on_pass` is called after every pass on the process holding the result.
+/// Only that process can count changed pixels, so it decides when the image
+/// is stable and broadcasts the decision to the others.
+pub fn filter_repeatedly<F>(
+    backend: &dyn MedianBackend,
+    img: AnyImage,
+    spec: &FilterSpec,
+    repetition: Repetition,
+    mut on_pass: F,
+) -> Result<Repeated, FilterError>
+where
+    F: FnMut(&Pass),
+{
+    let mut filtered = img;
+    let mut passes = 0;
+    let mut stable = false;
+    let mut processing_time_ms = 0.0;
+
+    while passes < repetition.max_passes {
+        let start = Instant::now();
+        let output = backend.filter(&filtered, spec)?;
+        processing_time_ms += start.elapsed().as_secs_f64() * 1000.0;
+        passes += 1;
+
+        if backend.is_root() {
+            let changed = output.changed_pixels(&filtered);
+            on_pass(&Pass { number: passes, input: &filtered, output: &output, changed });
+            stable = repetition.stable_threshold.is_some_and(|threshold| changed < threshold);
+        }
+        filtered = output;
+
+        if repetition.stable_threshold.is_some() {
+            stable = backend.broadcast_flag(stable);
+            if stable {
+                break;
+            }
+        }
+    }
+
+    Ok(Repeated { image: filtered, passes, stable, processing_time_ms })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::create_backend;
+    use crate::test_util::impulse_image;
+
+    /// Changed pixel counts of every pass of `repetition` on the test image
+    fn run(repetition: Repetition) -> (Vec<usize>, Repeated) {
+        let seq = create_backend("seq").unwrap();
+        let img = AnyImage::U8(impulse_image(31, 23));
+        let mut changes = Vec::new();
+        let repeated = filter_repeatedly(seq.as_ref(), img, &FilterSpec::new(3), repetition, |pass| {
+            assert_eq!(pass.number, changes.len() + 1);
+            changes.push(pass.changed);
+        })
+        .unwrap();
+        assert_eq!(repeated.passes, changes.len());
+        (changes, repeated)
+    }
+
+    /// Every pass but the last changes at least `threshold` pixels, the last
+    /// one fewer
+    #[test]
+    fn stops_once_fewer_pixels_change_than_the_threshold() {
+        for threshold in [1, 5, 40] {
+            let (changes, repeated) = run(Repetition::until_stable(50, threshold));
+            let (last, earlier) = changes.split_last().unwrap();
+            assert!(repeated.stable);
+            assert!(*last < threshold, "{:?} with threshold {}", changes, threshold);
+            assert!(earlier.iter().all(|&c| c >= threshold), "{:?} with threshold {}", changes, threshold);
+        }
+    }
+
+    #[test]
+    fn stops_at_the_pass_limit() {
+        let (changes, repeated) = run(Repetition::until_stable(2, 1));
+        assert_eq!(changes.len(), 2);
+        assert!(!repeated.stable);
+
+        let (changes, repeated) = run(Repetition::fixed(4));
+        assert_eq!(changes.len(), 4);
+        assert!(!repeated.stable);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::backend::FilterError;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Shape of the filter window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelShape {
+    /// Full rectangle
+    Square,
+    /// Center row and center column
+    Cross,
+    /// Pixels within L1 distance of the center
+    Diamond,
+    /// Pixels within the inscribed ellipse
+    Disk,
+    /// User-supplied mask
+    Custom,
+}
+
+impl FromStr for KernelShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(KernelShape::Square),
+            "cross" => Ok(KernelShape::Cross),
+            "diamond" => Ok(KernelShape::Diamond),
+            "disk" => Ok(KernelShape::Disk),
+            _ => Err(format!(
+                "Unknown kernel shape '{}'. Available: square, cross, diamond, disk",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KernelShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KernelShape::Square => "square",
+            KernelShape::Cross => "cross",
+            KernelShape::Diamond => "diamond",
+            KernelShape::Disk => "disk",
+            KernelShape::Custom => "custom",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Footprint of the filter window: which pixels around the center take part
+/// in the median
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel {
+    shape: KernelShape,
+    width: usize,
+    height: usize,
+    offsets: Vec<(i32, i32)>,
+}
+
+impl Kernel {
+    /// Full `size` x `size` window
+    pub fn square(size: usize) -> Self {
+        Kernel::with_shape(KernelShape::Square, size, size)
+    }
+
+    /// Window of the given shape inscribed in a `width` x `height` rectangle
+    pub fn with_shape(shape: KernelShape, width: usize, height: usize) -> Self {
+        let rx = (width / 2) as i64;
+        let ry = (height / 2) as i64;
+
+        let mask = (0..width * height)
+            .map(|i| {
+                let dx = (i % width) as i64 - rx;
+                let dy = (i / width) as i64 - ry;
+                match shape {
+                    KernelShape::Square | KernelShape::Custom => true,
+                    KernelShape::Cross => dx == 0 || dy == 0,
+                    KernelShape::Diamond => dx.abs() * ry + dy.abs() * rx <= rx * ry,
+                    KernelShape::Disk => dx * dx * ry * ry + dy * dy * rx * rx <= rx * rx * ry * ry,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Kernel::from_parts(shape, width, height, &mask)
+    }
+
+    /// Custom footprint from a row-major boolean mask
+    pub fn from_mask(width: usize, height: usize, mask: &[bool]) -> Result<Self, FilterError> {
+        if mask.len() != width * height {
+            return Err(FilterError::InvalidMask(format!(
+                "expected {} values for a {}x{} mask, got {}",
+                width * height,
+                width,
+                height,
+                mask.len()
+            )));
+        }
+        if !mask.contains(&true) {
+            return Err(FilterError::InvalidMask("mask selects no pixels".to_string()));
+        }
+
+        Ok(Kernel::from_parts(KernelShape::Custom, width, height, mask))
+    }
+
+    /// Load a custom footprint from a file
+    ///
+    /// `.txt` files hold one mask row per line, with `1`, `#`, `x` or `X`
+    /// marking selected pixels and `0` or `.` unselected ones. Any other
+    /// file is read as an image, where non-black pixels are selected.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FilterError> {
+        let path = path.as_ref();
+        let is_text = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("txt"));
+
+        if is_text {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| FilterError::InvalidMask(format!("{}: {}", path.display(), e)))?;
+            Kernel::parse_text(&text)
+        } else {
+            let img = image::open(path)
+                .map_err(|e| FilterError::InvalidMask(format!("{}: {}", path.display(), e)))?
+                .to_luma8();
+            let (width, height) = img.dimensions();
+            let mask: Vec<bool> = img.pixels().map(|p| p[0] > 0).collect();
+            Kernel::from_mask(width as usize, height as usize, &mask)
+        }
+    }
+
+    fn parse_text(text: &str) -> Result<Self, FilterError> {
+        let mut mask = Vec::new();
+        let mut width = None;
+        let mut height = 0;
+
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let row = line
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| match c {
+                    '1' | '#' | 'x' | 'X' => Ok(true),
+                    '0' | '.' => Ok(false),
+                    _ => Err(FilterError::InvalidMask(format!("unexpected character '{}'", c))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if *width.get_or_insert(row.len()) != row.len() {
+                return Err(FilterError::InvalidMask("mask rows differ in length".to_string()));
+            }
+            mask.extend(row);
+            height += 1;
+        }
+
+        Kernel::from_mask(width.unwrap_or(0), height, &mask)
+    }
+
+    /// Load a weight matrix for weighted medians from a text file
+    ///
+    /// Each line holds one row of non-negative integer weights separated by
+    /// whitespace or commas. Pixels of weight 0 are left out of the
+    /// footprint. Returns the kernel and the weights of its pixels in the
+    /// order of `offsets()`.
+    pub fn load_weights<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<u32>), FilterError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| FilterError::InvalidMask(format!("{}: {}", path.display(), e)))?;
+        Kernel::parse_weights(&text)
+    }
+
+    fn parse_weights(text: &str) -> Result<(Self, Vec<u32>), FilterError> {
+        let mut weights = Vec::new();
+        let mut width = None;
+        let mut height = 0;
+
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let row = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|w| !w.is_empty())
+                .map(|w| {
+                    w.parse::<u32>()
+                        .map_err(|_| FilterError::InvalidMask(format!("invalid weight '{}'", w)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if *width.get_or_insert(row.len()) != row.len() {
+                return Err(FilterError::InvalidMask("weight rows differ in length".to_string()));
+            }
+            weights.extend(row);
+            height += 1;
+        }
+
+        let mask: Vec<bool> = weights.iter().map(|&w| w > 0).collect();
+        let kernel = Kernel::from_mask(width.unwrap_or(0), height, &mask)?;
+        weights.retain(|&w| w > 0);
+        Ok((kernel, weights))
+    }
+
+    /// Weights of a center-weighted median: `weight` for the center pixel
+    /// and 1 for the others
+    pub fn center_weights(&self, weight: u32) -> Vec<u32> {
+        self.offsets
+            .iter()
+            .map(|&offset| if offset == (0, 0) { weight } else { 1 })
+            .collect()
+    }
+
+    fn from_parts(shape: KernelShape, width: usize, height: usize, mask: &[bool]) -> Self {
+        let rx = (width / 2) as i32;
+        let ry = (height / 2) as i32;
+
+        let offsets = mask
+            .iter()
+            .enumerate()
+            .filter(|(_, &selected)| selected)
+            .map(|(i, _)| ((i % width) as i32 - rx, (i / width) as i32 - ry))
+            .collect();
+
+        Kernel {
+            shape,
+            width,
+            height,
+            offsets,
+        }
+    }
+
+    /// Shape the kernel was built from
+    pub fn shape(&self) -> KernelShape {
+        self.shape
+    }
+
+    /// Width of the bounding rectangle
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of the bounding rectangle
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Offsets (dx, dy) from the center of every selected pixel, row by row
+    pub fn offsets(&self) -> &[(i32, i32)] {
+        &self.offsets
+    }
+
+    /// Number of selected pixels
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether the kernel selects no pixels
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Whether every pixel of the bounding rectangle is selected
+    pub fn is_full(&self) -> bool {
+        self.offsets.len() == self.width * self.height
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}x{}", self.shape, self.width, self.height)
+    }
+}
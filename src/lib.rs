@@ -0,0 +1,34 @@
+//! Median filter library.
+//!
+//! Exposes the sequential, parallel (Rayon), GPU (WGPU) and distributed (MPI)
+//! median filter implementations together with the shared image type, noise
+//! generation and quality metrics used by the `median-filter` binary.
+
+pub mod adaptive;
+pub mod backend;
+pub mod color;
+pub mod distributed;
+pub mod gpu;
+pub mod histogram;
+pub mod iterations;
+pub mod kernel;
+pub mod noise;
+pub mod parallel;
+pub mod region;
+pub mod sample;
+pub mod selection;
+pub mod sequential;
+pub mod shared;
+pub mod simd;
+pub mod switching;
+#[cfg(test)]
+mod test_util;
+pub mod vector;
+
+pub use backend::{available_backends, create_backend, FilterError, FilterSpec, MedianBackend};
+pub use color::ColorSpace;
+pub use kernel::{Kernel, KernelShape};
+pub use noise::{NoiseModel, NoiseSpec};
+pub use region::{Rect, Region};
+pub use sample::{Sample, SampleType};
+pub use shared::{add_noise, add_noise_with_rng, calculate_psnr, calculate_ssim, noise_rng, AnyImage, Image};
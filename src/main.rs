@@ -1,41 +1,241 @@
-mod shared;
-mod sequential;
-mod parallel;
-mod gpu;
-mod distributed;
-
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use csv::WriterBuilder;
+use median_filter::backend::{self, FilterSpec};
+use median_filter::color::ColorSpace;
+use median_filter::iterations::{self, Repeated, Repetition};
+use median_filter::kernel::{Kernel, KernelShape};
+use median_filter::noise::{NoiseModel, NoiseSpec};
+use median_filter::region::{Rect, Region};
+use median_filter::shared::{self, noise_rng, AlphaMode, Algorithm, BorderMode, Image, RankFilter, VectorNorm};
+use median_filter::switching::{self, ImpulseDetector};
 use serde::Serialize;
 use std::fs::{create_dir_all, OpenOptions};
-use std::path::PathBuf;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
 #[command(name = "median-filter")]
 #[command(about = "Apply median filter to images with various methods", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input image path
-    #[arg(short, long)]
-    input: PathBuf,
+    #[arg(short, long, required = true)]
+    input: Option<PathBuf>,
 
     /// Output image path
-    #[arg(short, long)]
-    output: PathBuf,
+    #[arg(short, long, required = true)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    noise: NoiseArgs,
+
+    /// The input is already noisy: filter it as it is, without adding noise
+    #[arg(long)]
+    noisy_input: bool,
 
-    /// Noise level (0.0 to 1.0)
-    #[arg(short, long, default_value = "0.0")]
-    noise: f32,
+    /// Clean ground-truth image for PSNR and SSIM (default: the input, when noise is added to it)
+    #[arg(long)]
+    reference: Option<PathBuf>,
 
     /// Method: seq, par, dist, gpu
     #[arg(short, long, default_value = "seq")]
     method: String,
 
-    /// Kernel size (3 or 5)
+    /// Kernel size (odd; at most 127 on CPU, 63 on GPU)
     #[arg(short, long, default_value = "3")]
     kernel: usize,
+
+    /// Kernel height, if different from its width (e.g. -k 1 --kernel-height 9)
+    #[arg(long)]
+    kernel_height: Option<usize>,
+
+    /// Kernel shape: square, cross, diamond, disk
+    #[arg(long, default_value = "square")]
+    shape: KernelShape,
+
+    /// Custom kernel mask (.txt with 1/0 rows, or an image); overrides size and shape
+    #[arg(long)]
+    mask: Option<PathBuf>,
+
+    /// Weighted median with a weight matrix (.txt with rows of integers; 0 leaves a pixel out); overrides size, shape and mask
+    #[arg(long, conflicts_with_all = ["mask", "center_weight"])]
+    weights: Option<PathBuf>,
+
+    /// Center-weighted median: the center pixel counts this many times
+    #[arg(long)]
+    center_weight: Option<u32>,
+
+    /// Border handling: reflect101, reflect, replicate, wrap, constant[:value], shrink
+    #[arg(long, default_value = "reflect101")]
+    border: BorderMode,
+
+    /// Median algorithm for CPU methods: auto, sort, constant-time, huang, simd
+    #[arg(long, default_value = "auto")]
+    algorithm: Algorithm,
+
+    /// Rank filter: median, min, max, percentile:<0-100>, trimmed-mean:<0-0.5> (fraction dropped from each end)
+    #[arg(long, visible_alias = "rank", default_value = "median")]
+    filter: RankFilter,
+
+    /// Alpha channel of RGBA images: filter, keep, opaque[:threshold] (median over opaque neighbors only)
+    #[arg(long, default_value = "filter")]
+    alpha: AlphaMode,
+
+    /// Vector median with the given pixel distance (l1, l2) instead of per-channel medians (CPU methods, kernels up to 15x15)
+    #[arg(long)]
+    vector_median: Option<VectorNorm>,
+
+    /// Adaptive median: grow the window per pixel from the kernel size up to this size, replacing only impulses (CPU methods)
+    #[arg(long)]
+    adaptive: Option<usize>,
+
+    /// Switching median: replace only pixels the detector marks as impulses: extreme, rank-order[:threshold]
+    #[arg(long)]
+    switching: Option<ImpulseDetector>,
+
+    /// Save the switching detector's noise mask (white = impulse) to this path
+    #[arg(long, requires = "switching")]
+    noise_mask: Option<PathBuf>,
+
+    /// Color space to filter in: rgb, ycbcr, hsv, lab
+    #[arg(long, default_value = "rgb")]
+    color_space: ColorSpace,
+
+    /// Comma-separated color channels to filter, named after the color space (e.g. y, or cb,cr); default all
+    #[arg(long)]
+    channels: Option<String>,
+
+    /// Compute PSNR and SSIM on the luma (BT.601) channel only
+    #[arg(long)]
+    luma_metrics: bool,
+
+    /// Filter only this rectangle, given as x,y,width,height; other pixels are left untouched
+    #[arg(long)]
+    roi: Option<Rect>,
+
+    /// Filter only the pixels that are nonzero in this mask image (same size as the input)
+    #[arg(long, conflicts_with = "roi")]
+    roi_mask: Option<PathBuf>,
+
+    /// Compute PSNR and SSIM over the region of interest only
+    #[arg(long)]
+    roi_metrics: bool,
+
+    /// Number of filter passes; with --until-stable the maximum number of passes (default 50)
+    #[arg(long)]
+    iterations: Option<usize>,
+
+    /// Repeat filtering until fewer than --stable-threshold pixels change in a pass
+    #[arg(long)]
+    until_stable: bool,
+
+    /// Number of changed pixels below which --until-stable stops
+    #[arg(long, default_value = "1", requires = "until_stable")]
+    stable_threshold: usize,
+}
+
+/// Pass limit of `--until-stable` when `--iterations` is not given
+const MAX_STABLE_ITERATIONS: usize = 50;
+
+impl Args {
+    /// Input image path; clap requires it unless a subcommand is given
+    fn input(&self) -> &Path {
+        self.input.as_deref().expect("--input is required without a subcommand")
+    }
+
+    /// Output image path; clap requires it unless a subcommand is given
+    fn output(&self) -> &Path {
+        self.output.as_deref().expect("--output is required without a subcommand")
+    }
+
+    /// Clean image the metrics compare against: the reference if given, or
+    /// the input when noise is added to it; `None` when there is none
+    fn reference(&self) -> Option<&Path> {
+        match &self.reference {
+            Some(path) => Some(path),
+            None if !self.noisy_input && self.noise.spec().is_active() => Some(self.input()),
+            None => None,
+        }
+    }
+}
+
+/// Noise options, shared by filtering and the `noise` subcommand
+#[derive(clap::Args, Debug)]
+struct NoiseArgs {
+    /// Noise level (0.0 to 1.0): fraction of pixels, or of lines for stripes, to corrupt
+    #[arg(short = 'n', long = "noise", default_value = "0.0")]
+    level: f32,
+
+    /// Noise model: salt-and-pepper, random-impulse, stripe[:rows|columns], gaussian:<sigma>, speckle:<sigma>, poisson[:peak]
+    #[arg(long = "noise-model", default_value = "salt-and-pepper")]
+    model: NoiseModel,
+
+    /// Corrupt exactly the --noise fraction of distinct pixels (otherwise repeated hits lower it)
+    #[arg(long = "exact-noise")]
+    exact: bool,
+
+    /// Fraction of impulses that are salt (white), the rest are pepper (black)
+    #[arg(long, default_value = "0.5")]
+    salt_ratio: f32,
+
+    /// Draw the impulse value for every color channel separately (colored impulses)
+    #[arg(long = "colored-noise")]
+    colored: bool,
+
+    /// Seed for the noise generator; runs with the same seed get identical noise (random if omitted)
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl NoiseArgs {
+    /// Noise settings given on the command line
+    fn spec(&self) -> NoiseSpec {
+        NoiseSpec {
+            model: self.model,
+            salt_ratio: self.salt_ratio,
+            exact: self.exact,
+            per_channel: self.colored,
+            ..NoiseSpec::new(self.level)
+        }
+    }
+
+    /// The given seed, or a random one so every run can be repeated
+    fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(rand::random)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add noise to a clean image and save the noisy image, without filtering
+    Noise(NoiseCommand),
+}
+
+#[derive(clap::Args, Debug)]
+struct NoiseCommand {
+    /// Clean input image path
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Noisy output image path (use a lossless format such as PNG or TIFF)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Save the mask of corrupted pixels (white = corrupted) to this path
+    #[arg(long)]
+    corruption_mask: Option<PathBuf>,
+
+    #[command(flatten)]
+    noise: NoiseArgs,
 }
 
+/// One row of `results/results.csv`
+///
+/// The first nine columns are the original schema; later columns are only
+/// ever appended, so older analysis code keeps reading the file. Keep
+/// `MEASUREMENT_COLUMNS` in the same order.
 #[derive(Serialize)]
 struct Measurement {
     timestamp: String,
@@ -45,124 +245,332 @@ struct Measurement {
     processing_time_ms: f64,
     method: String,
     num_processes: i32,
-    psnr: f64,
-    ssim: f64,
+    psnr: Option<f64>,
+    ssim: Option<f64>,
+    kernel_shape: String,
+    algorithm: String,
+    iterations: usize,
+    seed: Option<u64>,
+    noise_model: String,
+    reference: Option<String>,
 }
 
+/// Header of `results/results.csv`, one name per `Measurement` field
+const MEASUREMENT_COLUMNS: &[&str] = &[
+    "timestamp",
+    "image",
+    "kernel_size",
+    "noise_level",
+    "processing_time_ms",
+    "method",
+    "num_processes",
+    "psnr",
+    "ssim",
+    "kernel_shape",
+    "algorithm",
+    "iterations",
+    "seed",
+    "noise_model",
+    "reference",
+];
+
 fn validate_args(args: &Args) -> Result<(), String> {
-    if args.kernel != 3 && args.kernel != 5 {
-        return Err("Kernel size must be 3 or 5".to_string());
+    args.noise.spec().validate()?;
+
+    if args.noisy_input && args.noise.spec().is_active() {
+        return Err("--noisy-input filters the input as it is and takes no noise options".to_string());
+    }
+
+    if (args.luma_metrics || args.roi_metrics) && args.reference().is_none() {
+        return Err("--luma-metrics and --roi-metrics need a clean reference: add noise or give --reference".to_string());
+    }
+
+    if args.roi_metrics && args.roi.is_none() && args.roi_mask.is_none() {
+        return Err("--roi-metrics needs a region, given with --roi or --roi-mask".to_string());
     }
 
-    if args.noise < 0.0 || args.noise > 1.0 {
-        return Err("Noise level must be between 0.0 and 1.0".to_string());
+    if args.iterations == Some(0) {
+        return Err("Number of iterations must be at least 1".to_string());
     }
 
     Ok(())
 }
 
+/// Check that the metrics can compare `reference` with `input`
+fn check_reference(input: &shared::AnyImage, reference: &shared::AnyImage) -> Result<(), String> {
+    let describe = |img: &shared::AnyImage| {
+        format!("{}x{} {} {}", img.width(), img.height(), img.color(), img.sample_type())
+    };
+    if describe(input) != describe(reference) {
+        return Err(format!("The reference image is {}, but the input is {}", describe(reference), describe(input)));
+    }
+    Ok(())
+}
+
+fn exit_with_error(e: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+}
+
+/// Add `noise` drawn from `seed` to `img`, report it and return the mask of
+/// corrupted pixels
+fn add_noise(img: &mut shared::AnyImage, noise: &NoiseSpec, seed: u64) -> Image<u8> {
+    if noise.model.is_impulse() {
+        println!("Adding {}% {} noise (seed: {})...", noise.level * 100.0, noise, seed);
+    } else {
+        println!("Adding {} noise (seed: {})...", noise, seed);
+    }
+    let corrupted = img.apply_noise(noise, &mut noise_rng(seed));
+    let count = corrupted.data.iter().filter(|&&v| v > 0).count();
+    println!("Corrupted pixels: {} ({:.2}%)", count, 100.0 * count as f64 / corrupted.data.len() as f64);
+    corrupted
+}
+
+/// `median-filter noise`: save a noisy copy of the input and optionally the
+/// mask of corrupted pixels
+fn write_noisy_image(command: &NoiseCommand) {
+    let noise = command.noise.spec();
+    if let Err(e) = noise.validate() {
+        exit_with_error(e);
+    }
+    if !noise.is_active() {
+        exit_with_error("No noise to add; give a noise level with --noise");
+    }
+
+    println!("Loading image: {:?}", command.input);
+    let mut img = shared::AnyImage::load(&command.input).unwrap_or_else(|e| exit_with_error(e));
+    let corrupted = add_noise(&mut img, &noise, command.noise.seed());
+
+    println!("Saving noisy image: {:?}", command.output);
+    img.save(&command.output).expect("Failed to save noisy image");
+    if let Some(path) = &command.corruption_mask {
+        println!("Saving corruption mask: {:?}", path);
+        corrupted.save(path).expect("Failed to save corruption mask");
+    }
+
+    println!("Done!");
+}
+
 fn main() {
     let args = Args::parse();
 
+    if let Some(Command::Noise(command)) = &args.command {
+        write_noisy_image(command);
+        return;
+    }
+
     // Validate arguments
     if let Err(e) = validate_args(&args) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
-    }
-
-    // Load original image (for PSNR/SSIM calculation)
-    println!("Loading image: {:?}", args.input);
-    let original = shared::Image::load(&args.input).expect("Failed to load image");
-
-    // Special handling for distributed method
-    if args.method == "dist" {
-        let start = Instant::now();
-        let (filtered, rank, num_processes) = distributed::apply_median_filter_mpi(
-            &args.input,
-            args.noise,
-            args.kernel,
-        );
-        let duration = start.elapsed();
-        let processing_time_ms = duration.as_secs_f64() * 1000.0;
-
-        // Only root process saves output and logs
-        if rank == 0 {
-            println!("Processing time: {:.2} ms", processing_time_ms);
-
-            // Calculate PSNR and SSIM
-            let psnr = shared::calculate_psnr(&original, &filtered);
-            let ssim = shared::calculate_ssim(&original, &filtered);
-            println!("PSNR: {:.2} dB, SSIM: {:.4}", psnr, ssim);
-
-            println!("Saving output: {:?}", args.output);
-            filtered.save(&args.output).expect("Failed to save output image");
-            save_measurement(&args, processing_time_ms, Some(num_processes), psnr, ssim);
-            println!("Done!");
+        exit_with_error(e);
+    }
+
+    // Create the filtering backend and check it supports the requested filter
+    let (kernel, weights) = match (&args.weights, &args.mask) {
+        (Some(path), _) => {
+            let (kernel, weights) = Kernel::load_weights(path).unwrap_or_else(|e| exit_with_error(e));
+            (kernel, Some(weights))
         }
-        return;
+        (None, Some(path)) => (Kernel::load(path).unwrap_or_else(|e| exit_with_error(e)), None),
+        (None, None) => {
+            let height = args.kernel_height.unwrap_or(args.kernel);
+            (Kernel::with_shape(args.shape, args.kernel, height), None)
+        }
+    };
+    let weights = weights.or_else(|| args.center_weight.map(|w| kernel.center_weights(w)));
+    let channels = args.channels.as_deref().map(|list| {
+        args.color_space.parse_channels(list).unwrap_or_else(|e| exit_with_error(e))
+    });
+    let region = match (args.roi, &args.roi_mask) {
+        (Some(rect), _) => Some(Region::Rect(rect)),
+        (None, Some(path)) => Some(Region::load_mask(path).unwrap_or_else(|e| exit_with_error(e))),
+        (None, None) => None,
+    };
+    let spec = FilterSpec {
+        border: args.border,
+        algorithm: args.algorithm,
+        rank: args.filter,
+        alpha: args.alpha,
+        vector: args.vector_median,
+        adaptive: args.adaptive,
+        weights,
+        color_space: args.color_space,
+        channels,
+        region,
+        switching: args.switching,
+        ..FilterSpec::from_kernel(kernel)
+    };
+    let backend = backend::create_backend(&args.method).unwrap_or_else(|e| exit_with_error(e));
+    if let Err(e) = spec.validate(backend.max_kernel_size()) {
+        exit_with_error(e);
     }
 
-    // Clone original for noisy version
-    let mut img = shared::Image::load(&args.input).expect("Failed to load image");
+    // Load the input image
+    println!("Loading image: {:?}", args.input());
+    let input = shared::AnyImage::load(args.input()).expect("Failed to load image");
+    println!("Image: {}x{} {} {}", input.width(), input.height(), input.color(), input.sample_type());
 
-    // Add noise if requested
-    if args.noise > 0.0 {
-        println!("Adding {}% noise...", args.noise * 100.0);
-        shared::add_noise(&mut img, args.noise);
+    // Every rank loads the images, so every rank rejects a bad region or reference
+    if let Some(region) = &spec.region {
+        if let Err(e) = region.validate(input.width(), input.height()) {
+            exit_with_error(e);
+        }
+        if let Some(rect) = args.roi {
+            println!("Region of interest: {}", rect);
+        } else if let Some(path) = &args.roi_mask {
+            println!("Region of interest: mask {:?}", path);
+        }
     }
 
-    // Apply median filter based on method
-    println!("Applying median filter (method: {}, kernel: {}x{})...",
-             args.method, args.kernel, args.kernel);
-
-    let start = Instant::now();
-    let filtered = match args.method.as_str() {
-        "seq" => sequential::apply_median_filter(&img, args.kernel),
-        "par" => parallel::apply_median_filter(&img, args.kernel),
-        "gpu" => gpu::apply_median_filter(&img, args.kernel),
-        _ => {
-            eprintln!("Error: Unknown method '{}'. Available: seq, par, gpu, dist", args.method);
-            std::process::exit(1);
+    // PSNR/SSIM compare against a clean image: the reference, or the input
+    // itself before noise is added to it
+    let reference = match (&args.reference, args.reference()) {
+        (Some(path), _) => {
+            println!("Loading reference: {:?}", path);
+            let reference = shared::AnyImage::load(path)
+                .unwrap_or_else(|e| exit_with_error(format!("Failed to load reference {:?}: {}", path, e)));
+            if let Err(e) = check_reference(&input, &reference) {
+                exit_with_error(e);
+            }
+            Some(reference)
         }
+        (None, Some(_)) => Some(input.clone()),
+        (None, None) => {
+            println!("No clean reference (no noise added and no --reference), PSNR and SSIM are N/A");
+            None
+        }
+    };
+    let mut img = input;
+
+    // Add noise if requested (MPI workers receive the image from the root);
+    // an input that is already noisy is filtered as it is
+    let noise = args.noise.spec();
+    let seed = (!args.noisy_input && noise.is_active()).then(|| args.noise.seed());
+    if args.noisy_input {
+        println!("Input is already noisy, adding no noise");
+    } else if let (Some(seed), true) = (seed, backend.is_root()) {
+        add_noise(&mut img, &noise, seed);
+    }
+
+    // Apply median filter with the selected backend
+    let algorithm = backend.algorithm(&spec, img.sample_type());
+    println!("Applying {} filter (method: {}, algorithm: {}, kernel: {}, color space: {})...",
+             spec.rank, backend.name(), algorithm, spec.kernel, spec.color_space);
+
+    // Filter repeatedly in memory; every pass starts from the previous output
+    let max_iterations = args.iterations.unwrap_or(if args.until_stable { MAX_STABLE_ITERATIONS } else { 1 });
+    let metric_mask = spec
+        .region
+        .as_ref()
+        .filter(|_| args.roi_metrics)
+        .map(|region| region.pixels(img.width(), img.height()));
+    let psnr_of = |filtered: &shared::AnyImage| {
+        reference.as_ref().map(|reference| {
+            if args.luma_metrics {
+                reference.luminance().psnr_in(&filtered.luminance(), metric_mask.as_deref())
+            } else {
+                reference.psnr_in(filtered, metric_mask.as_deref())
+            }
+        })
     };
-    let duration = start.elapsed();
-    let processing_time_ms = duration.as_secs_f64() * 1000.0;
+    let repetition = if args.until_stable {
+        Repetition::until_stable(max_iterations, args.stable_threshold)
+    } else {
+        Repetition::fixed(max_iterations)
+    };
+    let mut noise_mask = None;
+    let repeated = iterations::filter_repeatedly(backend.as_ref(), img, &spec, repetition, |pass| {
+        if max_iterations > 1 {
+            let pixels = (pass.output.width() * pass.output.height()) as f64;
+            let psnr = psnr_of(pass.output).map(|psnr| format!(", PSNR: {:.2} dB", psnr)).unwrap_or_default();
+            println!("Iteration {}: {} pixels changed ({:.2}%){}",
+                     pass.number, pass.changed, 100.0 * pass.changed as f64 / pixels, psnr);
+        }
+
+        // The noise mask collects the impulses the switching median
+        // replaced in every pass
+        if let Some(detector) = spec.switching {
+            let mask = switching::noise_mask(pass.input, pass.output, detector, spec.region.as_ref());
+            noise_mask = Some(match noise_mask.take() {
+                Some(previous) => switching::union_mask(previous, &mask),
+                None => mask,
+            });
+        }
+    })
+    .unwrap_or_else(|e| exit_with_error(e));
+    let Repeated { image: filtered, passes: iterations, stable, processing_time_ms } = repeated;
 
+    // Only the root process saves output and logs
+    if !backend.is_root() {
+        return;
+    }
+
+    if args.until_stable {
+        if stable {
+            println!("Stable after {} iteration(s)", iterations);
+        } else {
+            println!("Not stable after the maximum of {} iterations", iterations);
+        }
+    }
     println!("Processing time: {:.2} ms", processing_time_ms);
 
-    // Calculate PSNR and SSIM
-    let psnr = shared::calculate_psnr(&original, &filtered);
-    let ssim = shared::calculate_ssim(&original, &filtered);
-    println!("PSNR: {:.2} dB, SSIM: {:.4}", psnr, ssim);
+    // Calculate PSNR and SSIM against the clean reference, on all channels
+    // or the luma only, over the whole image or the region of interest
+    let mask = metric_mask.as_deref();
+    let metrics = reference.as_ref().map(|reference| {
+        if args.luma_metrics {
+            let (reference, filtered) = (reference.luminance(), filtered.luminance());
+            (reference.psnr_in(&filtered, mask), reference.ssim_in(&filtered, mask))
+        } else {
+            (reference.psnr_in(&filtered, mask), reference.ssim_in(&filtered, mask))
+        }
+    });
+    match metrics {
+        Some((psnr, ssim)) => println!("PSNR: {:.2} dB, SSIM: {:.4}{}{}", psnr, ssim,
+                                       if args.luma_metrics { " (luma)" } else { "" },
+                                       if mask.is_some() { " (region)" } else { "" }),
+        None => println!("PSNR: N/A, SSIM: N/A (no clean reference)"),
+    }
 
     // Save output image
-    println!("Saving output: {:?}", args.output);
-    filtered.save(&args.output).expect("Failed to save output image");
+    println!("Saving output: {:?}", args.output());
+    filtered.save(args.output()).expect("Failed to save output image");
+
+    if let Some(mask) = &noise_mask {
+        let impulses = mask.data.iter().filter(|&&v| v > 0).count();
+        println!("Impulses detected: {} ({:.2}%)", impulses, 100.0 * impulses as f64 / mask.data.len() as f64);
+        if let Some(path) = &args.noise_mask {
+            println!("Saving noise mask: {:?}", path);
+            mask.save(path).expect("Failed to save noise mask");
+        }
+    }
 
     // Save measurement to CSV
-    save_measurement(&args, processing_time_ms, None, psnr, ssim);
+    save_measurement(&args, &spec, &algorithm, seed, iterations, processing_time_ms, backend.num_processes(), metrics);
 
     println!("Done!");
 }
 
+#[allow(clippy::too_many_arguments)]
 fn save_measurement(
     args: &Args,
+    spec: &FilterSpec,
+    algorithm: &str,
+    seed: Option<u64>,
+    iterations: usize,
     processing_time_ms: f64,
-    num_processes: Option<i32>,
-    psnr: f64,
-    ssim: f64,
+    num_processes: i32,
+    metrics: Option<(f64, f64)>,
 ) {
     // Create results directory if it doesn't exist
     create_dir_all("results").expect("Failed to create results directory");
 
-    let csv_path = "results/results.csv";
-
-    // Check if file exists to determine if we need to write headers
-    let file_exists = std::path::Path::new(csv_path).exists();
+    let csv_path = Path::new("results/results.csv");
+    // Headers are written only to a new file; one with other columns is
+    // moved aside first
+    let file_exists = csv_path.exists() && !header_outdated(csv_path);
 
     let file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)  // Append instead of overwrite
         .open(csv_path)
         .expect("Failed to open CSV file");
@@ -173,18 +581,51 @@ fn save_measurement(
 
     let measurement = Measurement {
         timestamp: chrono::Local::now().to_rfc3339(),
-        image: args.input.to_string_lossy().to_string(),
-        kernel_size: args.kernel,
-        noise_level: args.noise,
+        image: args.input().to_string_lossy().to_string(),
+        reference: args.reference().map(|path| path.to_string_lossy().to_string()),
+        kernel_size: spec.kernel.width(),
+        kernel_shape: spec.kernel.to_string(),
+        noise_level: args.noise.level,
+        noise_model: if args.noisy_input { "noisy-input".to_string() } else { args.noise.spec().to_string() },
+        seed,
         processing_time_ms,
         method: args.method.clone(),
-        num_processes: num_processes.unwrap_or(1),
-        psnr,
-        ssim,
+        algorithm: algorithm.to_string(),
+        iterations,
+        num_processes,
+        psnr: metrics.map(|(psnr, _)| psnr),
+        ssim: metrics.map(|(_, ssim)| ssim),
     };
 
     wtr.serialize(measurement).expect("Failed to serialize measurement");
     wtr.flush().expect("Failed to flush CSV writer");
 
-    println!("Measurement saved to: {}", csv_path);
+    println!("Measurement saved to: {}", csv_path.display());
+}
+
+/// Whether the CSV at `path` lacks the `MEASUREMENT_COLUMNS` header and
+/// needs one written
+///
+/// A file with a different header is renamed with its modification time
+/// appended (`results-<timestamp>.csv`) so that new rows start a fresh file
+/// instead of being appended under columns they do not match.
+fn header_outdated(path: &Path) -> bool {
+    let header = csv::Reader::from_path(path).and_then(|mut rdr| rdr.headers().cloned());
+    match &header {
+        Ok(header) if header.iter().eq(MEASUREMENT_COLUMNS.iter().copied()) => return false,
+        Ok(header) if header.is_empty() => return true,
+        _ => {}
+    }
+
+    let modified: chrono::DateTime<chrono::Local> = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_or_else(|_| chrono::Local::now(), Into::into);
+    let backup = path.with_file_name(format!("results-{}.csv", modified.format("%Y%m%d-%H%M%S")));
+    std::fs::rename(path, &backup).expect("Failed to move the outdated CSV file aside");
+    println!(
+        "{} has different columns than this version writes; moved it to {} and starting a new file",
+        path.display(),
+        backup.display()
+    );
+    true
 }
@@ -0,0 +1,432 @@
+//! Noise generation.
+//!
+//! Adds noise to clean images so the filters can be measured against the
+//! original. Impulse models (salt-and-pepper, random-valued impulses and
+//! stripes) corrupt a fraction of the pixels or lines and leave the rest
+//! intact, the case the median filter is built for; Gaussian, speckle and
+//! Poisson noise disturb every pixel. Every function draws from the
+//! generator it is given, so a seeded generator (`shared::noise_rng`)
+//! reproduces the same noise on every machine.
+
+use crate::sample::{Sample, SampleType};
+use crate::shared::{ColorType, Image};
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+/// Distribution of the noise added to an image
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NoiseModel {
+    /// Pixels set to black or white
+    #[default]
+    SaltAndPepper,
+    /// Pixels set to uniformly random values
+    RandomImpulse,
+    /// Whole rows or columns set to black or white, like dropped scan lines
+    Stripe(StripeDirection),
+    /// Additive zero-mean noise with this standard deviation, given on the
+    /// 8-bit scale and scaled to the image's sample type
+    Gaussian(f32),
+    /// Multiplicative noise: every sample is scaled by 1 + n, where n is
+    /// zero-mean Gaussian with this standard deviation
+    Speckle(f32),
+    /// Shot noise: every sample becomes a Poisson photon count, with this
+    /// many photons expected at white
+    Poisson(f32),
+}
+
+/// Lines hit by `NoiseModel::Stripe`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StripeDirection {
+    #[default]
+    Rows,
+    Columns,
+}
+
+impl NoiseModel {
+    /// Photons at white for `poisson` without a parameter
+    pub const DEFAULT_PEAK: f32 = 255.0;
+
+    /// Whether the model corrupts a fraction of the pixels (or lines) and
+    /// leaves the others intact
+    pub fn is_impulse(&self) -> bool {
+        matches!(self, NoiseModel::SaltAndPepper | NoiseModel::RandomImpulse | NoiseModel::Stripe(_))
+    }
+
+    /// Whether impulses are split into salt and pepper
+    fn has_salt(&self) -> bool {
+        matches!(self, NoiseModel::SaltAndPepper | NoiseModel::Stripe(_))
+    }
+}
+
+impl FromStr for NoiseModel {
+    type Err = String;
+
+    /// Parse a model name; `gaussian:<sigma>`, `speckle:<sigma>`,
+    /// `poisson[:peak]` and `stripe[:rows|columns]` carry their parameter
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let positive = |value: &str, what: &str| match value.parse::<f32>() {
+            Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+            _ => Err(format!("Invalid {} '{}', expected a positive number", what, value)),
+        };
+
+        match s.split_once(':') {
+            None if s == "salt-and-pepper" => Ok(NoiseModel::SaltAndPepper),
+            None if s == "random-impulse" => Ok(NoiseModel::RandomImpulse),
+            None if s == "stripe" => Ok(NoiseModel::Stripe(StripeDirection::Rows)),
+            None if s == "poisson" => Ok(NoiseModel::Poisson(NoiseModel::DEFAULT_PEAK)),
+            Some(("stripe", "rows")) => Ok(NoiseModel::Stripe(StripeDirection::Rows)),
+            Some(("stripe", "columns")) => Ok(NoiseModel::Stripe(StripeDirection::Columns)),
+            Some(("gaussian", sigma)) => positive(sigma, "standard deviation").map(NoiseModel::Gaussian),
+            Some(("speckle", sigma)) => positive(sigma, "standard deviation").map(NoiseModel::Speckle),
+            Some(("poisson", peak)) => positive(peak, "photon count").map(NoiseModel::Poisson),
+            _ => Err(format!(
+                "Unknown noise model '{}'. Available: salt-and-pepper, random-impulse, stripe[:rows|columns], \
+                 gaussian:<sigma>, speckle:<sigma>, poisson[:peak]",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NoiseModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseModel::SaltAndPepper => write!(f, "salt-and-pepper"),
+            NoiseModel::RandomImpulse => write!(f, "random-impulse"),
+            NoiseModel::Stripe(StripeDirection::Rows) => write!(f, "stripe:rows"),
+            NoiseModel::Stripe(StripeDirection::Columns) => write!(f, "stripe:columns"),
+            NoiseModel::Gaussian(sigma) => write!(f, "gaussian:{}", sigma),
+            NoiseModel::Speckle(sigma) => write!(f, "speckle:{}", sigma),
+            NoiseModel::Poisson(peak) => write!(f, "poisson:{}", peak),
+        }
+    }
+}
+
+/// Noise to add to an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseSpec {
+    pub model: NoiseModel,
+    /// Fraction of pixels (or lines, for stripes) to corrupt, 0.0 to 1.0;
+    /// impulse models only
+    pub level: f32,
+    /// Fraction of impulses that are salt (white); the rest are pepper (black)
+    pub salt_ratio: f32,
+    /// Corrupt exactly `level` of the distinct pixels; otherwise coordinates
+    /// are drawn with replacement and repeated hits lower the actual fraction
+    pub exact: bool,
+    /// Draw the impulse value separately for every color channel, giving
+    /// colored impulses instead of black, white or gray ones
+    pub per_channel: bool,
+}
+
+impl NoiseSpec {
+    /// Salt-and-pepper noise: equal parts salt and pepper, drawn with
+    /// replacement, whole pixels
+    pub fn new(level: f32) -> Self {
+        NoiseSpec {
+            model: NoiseModel::SaltAndPepper,
+            level,
+            salt_ratio: 0.5,
+            exact: false,
+            per_channel: false,
+        }
+    }
+
+    /// Whether applying the spec changes the image at all
+    pub fn is_active(&self) -> bool {
+        !self.model.is_impulse() || self.level > 0.0
+    }
+
+    /// Check the ranges of the level and the salt ratio, and that only
+    /// options the model uses are set
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.level) {
+            return Err("Noise level must be between 0.0 and 1.0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.salt_ratio) {
+            return Err("Salt ratio must be between 0.0 and 1.0".to_string());
+        }
+        if !self.model.is_impulse() && (self.level > 0.0 || self.exact || self.per_channel) {
+            return Err(format!(
+                "{} noise changes every pixel and takes no noise level, exact or colored option",
+                self.model
+            ));
+        }
+        if !self.model.has_salt() && self.salt_ratio != 0.5 {
+            return Err(format!("{} noise has no salt ratio", self.model));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NoiseSpec {
+    /// The model followed by the options that differ from their defaults,
+    /// e.g. `salt-and-pepper+exact+salt:0.8`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.model)?;
+        if self.exact {
+            write!(f, "+exact")?;
+        }
+        if self.salt_ratio != 0.5 {
+            write!(f, "+salt:{}", self.salt_ratio)?;
+        }
+        if self.per_channel {
+            write!(f, "+colored")?;
+        }
+        Ok(())
+    }
+}
+
+/// Add noise to the image
+///
+/// Only color channels are changed; alpha is left as is. Returns the
+/// ground-truth mask of corrupted pixels (white = corrupted): the pixels hit
+/// by impulse models, or the pixels whose value changed for the others.
+pub fn apply<T: Sample, R: Rng + ?Sized>(img: &mut Image<T>, noise: &NoiseSpec, rng: &mut R) -> Image<u8> {
+    let max = T::MAX.to_f64();
+    match noise.model {
+        NoiseModel::SaltAndPepper => impulses(img, noise, rng, |rng| impulse(noise.salt_ratio, rng)),
+        NoiseModel::RandomImpulse => impulses(img, noise, rng, uniform),
+        NoiseModel::Stripe(direction) => stripes(img, noise, direction, rng),
+        NoiseModel::Gaussian(sigma) => {
+            let sigma = sigma as f64 / 255.0 * max;
+            perturb(img, rng, |value, rng| value + sigma * gaussian(rng))
+        }
+        NoiseModel::Speckle(sigma) => perturb(img, rng, |value, rng| value * (1.0 + sigma as f64 * gaussian(rng))),
+        NoiseModel::Poisson(peak) => {
+            let peak = peak as f64;
+            perturb(img, rng, |value, rng| poisson(value / max * peak, rng) / peak * max)
+        }
+    }
+}
+
+/// Set `noise.level` of the pixels to values drawn by `value`, once per
+/// pixel or, with `noise.per_channel`, once per color channel
+fn impulses<T: Sample, R: Rng + ?Sized>(
+    img: &mut Image<T>,
+    noise: &NoiseSpec,
+    rng: &mut R,
+    mut value: impl FnMut(&mut R) -> T,
+) -> Image<u8> {
+    let total_pixels = img.width as usize * img.height as usize;
+    let mut mask = Image::new(img.width, img.height, ColorType::Gray);
+
+    let mut corrupt = |img: &mut Image<T>, pixel: usize, rng: &mut R| {
+        let i = pixel * img.channels();
+        let color = &mut img.data[i..i + img.color.color_channels()];
+        if noise.per_channel {
+            color.iter_mut().for_each(|sample| *sample = value(rng));
+        } else {
+            color.fill(value(rng));
+        }
+        mask.data[pixel] = u8::MAX;
+    };
+
+    if noise.exact {
+        let pixels_to_corrupt = (total_pixels as f64 * noise.level as f64).round() as usize;
+        for pixel in rand::seq::index::sample(rng, total_pixels, pixels_to_corrupt) {
+            corrupt(img, pixel, rng);
+        }
+    } else {
+        let pixels_to_corrupt = (total_pixels as f32 * noise.level) as u32;
+        for _ in 0..pixels_to_corrupt {
+            let x = rng.random_range(0..img.width);
+            let y = rng.random_range(0..img.height);
+            corrupt(img, y as usize * img.width as usize + x as usize, rng);
+        }
+    }
+
+    mask
+}
+
+/// Set `noise.level` of the rows or columns to salt or pepper, one value
+/// (or one color, with `noise.per_channel`) per line
+fn stripes<T: Sample, R: Rng + ?Sized>(
+    img: &mut Image<T>,
+    noise: &NoiseSpec,
+    direction: StripeDirection,
+    rng: &mut R,
+) -> Image<u8> {
+    let (width, height) = (img.width, img.height);
+    let (lines, length) = match direction {
+        StripeDirection::Rows => (height, width),
+        StripeDirection::Columns => (width, height),
+    };
+    let mut mask = Image::new(width, height, ColorType::Gray);
+
+    let hit: Vec<u32> = if noise.exact {
+        let count = (lines as f64 * noise.level as f64).round() as usize;
+        rand::seq::index::sample(rng, lines as usize, count).into_iter().map(|line| line as u32).collect()
+    } else {
+        (0..(lines as f32 * noise.level) as u32).map(|_| rng.random_range(0..lines)).collect()
+    };
+
+    let color_channels = img.color.color_channels();
+    for line in hit {
+        let color: Vec<T> = if noise.per_channel {
+            (0..color_channels).map(|_| impulse(noise.salt_ratio, rng)).collect()
+        } else {
+            vec![impulse(noise.salt_ratio, rng); color_channels]
+        };
+        for position in 0..length {
+            let (x, y) = match direction {
+                StripeDirection::Rows => (position, line),
+                StripeDirection::Columns => (line, position),
+            };
+            let pixel = y as usize * width as usize + x as usize;
+            let i = pixel * img.channels();
+            img.data[i..i + color_channels].copy_from_slice(&color);
+            mask.data[pixel] = u8::MAX;
+        }
+    }
+
+    mask
+}
+
+/// Replace every color sample by `noisy(value)`, clamped to black..white
+/// or to the wider range of the samples the image already holds
+///
+/// Integer samples always span black..white; float images keep values
+/// above white (HDR) or below black that they come with.
+fn perturb<T: Sample, R: Rng + ?Sized>(
+    img: &mut Image<T>,
+    rng: &mut R,
+    mut noisy: impl FnMut(f64, &mut R) -> f64,
+) -> Image<u8> {
+    let (channels, color_channels) = (img.channels(), img.color.color_channels());
+    let (low, high) = img
+        .data
+        .chunks_exact(channels)
+        .flat_map(|pixel| &pixel[..color_channels])
+        .fold((0.0, T::MAX.to_f64()), |(low, high), sample| {
+            (f64::min(low, sample.to_f64()), f64::max(high, sample.to_f64()))
+        });
+    let mut mask = Image::new(img.width, img.height, ColorType::Gray);
+
+    for (pixel, flag) in img.data.chunks_exact_mut(channels).zip(mask.data.iter_mut()) {
+        for sample in &mut pixel[..color_channels] {
+            let value = T::from_f64(noisy(sample.to_f64(), rng).clamp(low, high));
+            if value.total_cmp(sample).is_ne() {
+                *flag = u8::MAX;
+            }
+            *sample = value;
+        }
+    }
+
+    mask
+}
+
+/// Salt (white) with probability `salt_ratio`, pepper (black) otherwise
+fn impulse<T: Sample, R: Rng + ?Sized>(salt_ratio: f32, rng: &mut R) -> T {
+    if rng.random_bool(salt_ratio as f64) {
+        T::MAX
+    } else {
+        T::default()
+    }
+}
+
+/// Uniformly random sample: any integer value, or any float in 0.0..=1.0
+fn uniform<T: Sample, R: Rng + ?Sized>(rng: &mut R) -> T {
+    let max = T::MAX.to_f64();
+    match T::TYPE {
+        SampleType::F32 => T::from_f64(rng.random_range(0.0..=max)),
+        _ => T::from_f64(rng.random_range(0..=max as u32) as f64),
+    }
+}
+
+/// Standard normal sample (Box-Muller)
+fn gaussian<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.random::<f64>();
+    let u2: f64 = rng.random();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Poisson sample with mean `lambda`: Knuth's multiplication method for
+/// small means, the normal approximation above 30
+fn poisson<R: Rng + ?Sized>(lambda: f64, rng: &mut R) -> f64 {
+    if lambda >= 30.0 {
+        return (lambda + lambda.sqrt() * gaussian(rng)).round().max(0.0);
+    }
+
+    let limit = (-lambda).exp();
+    let mut product: f64 = rng.random();
+    let mut count = 0.0;
+    while product > limit {
+        product *= rng.random::<f64>();
+        count += 1.0;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::noise_rng;
+    use crate::test_util::impulse_image;
+
+    /// Noisy image and ground-truth mask of `noise` on the test image,
+    /// drawn from a generator seeded with `seed`
+    fn noisy(noise: &NoiseSpec, seed: u64) -> (Image, Image<u8>) {
+        let mut img = impulse_image(29, 17);
+        let mask = apply(&mut img, noise, &mut noise_rng(seed));
+        (img, mask)
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_noise() {
+        let models = [
+            NoiseModel::SaltAndPepper,
+            NoiseModel::RandomImpulse,
+            NoiseModel::Stripe(StripeDirection::Columns),
+            NoiseModel::Gaussian(20.0),
+            NoiseModel::Speckle(0.2),
+            NoiseModel::Poisson(NoiseModel::DEFAULT_PEAK),
+        ];
+        for model in models {
+            let level = if model.is_impulse() { 0.3 } else { 0.0 };
+            let noise = NoiseSpec { model, level, ..NoiseSpec::new(0.0) };
+            let (first, first_mask) = noisy(&noise, 7);
+            let (second, second_mask) = noisy(&noise, 7);
+            let (other, _) = noisy(&noise, 8);
+            assert!(first.data == second.data && first_mask.data == second_mask.data, "{}", model);
+            assert!(first.data != other.data, "{} ignores the seed", model);
+        }
+    }
+
+    /// Exact mode corrupts exactly `level` of the pixels, or of the lines
+    /// for stripes, however often the generator repeats a coordinate
+    #[test]
+    fn exact_mode_corrupts_exactly_the_level() {
+        let (width, height) = (29, 17);
+        let models = [NoiseModel::SaltAndPepper, NoiseModel::RandomImpulse, NoiseModel::Stripe(StripeDirection::Rows)];
+        for level in [0.0, 0.1, 0.37, 0.9, 1.0] {
+            for model in models {
+                for per_channel in [false, true] {
+                    let noise = NoiseSpec { model, exact: true, per_channel, ..NoiseSpec::new(level) };
+                    let (_, mask) = noisy(&noise, 3);
+                    let hit = mask.data.iter().filter(|&&v| v == u8::MAX).count();
+                    let expected = match model {
+                        NoiseModel::Stripe(_) => (height as f64 * level as f64).round() as usize * width,
+                        _ => ((width * height) as f64 * level as f64).round() as usize,
+                    };
+                    assert_eq!(hit, expected, "{} at level {}", noise, level);
+                }
+            }
+        }
+    }
+
+    /// Gaussian, speckle and Poisson noise keep float samples above white
+    /// within the image's range instead of clipping them to 1.0
+    #[test]
+    fn float_noise_keeps_hdr_values() {
+        let data: Vec<f32> = (0..300).map(|i| i as f32 / 100.0).collect();
+        for model in [NoiseModel::Gaussian(20.0), NoiseModel::Speckle(0.2), NoiseModel::Poisson(255.0)] {
+            let mut img = Image { data: data.clone(), width: 10, height: 10, color: ColorType::Rgb };
+            apply(&mut img, &NoiseSpec { model, ..NoiseSpec::new(0.0) }, &mut noise_rng(5));
+            let above_white = img.data.iter().filter(|&&v| v > 1.0).count();
+            assert!(above_white > 150, "{} clipped HDR values", model);
+            assert!(img.data.iter().all(|&v| (0.0..=2.99).contains(&v)), "{} left the input range", model);
+        }
+    }
+}
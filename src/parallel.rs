@@ -1,38 +1,70 @@
-use crate::shared::{collect_neighborhood, median_rgb, Image};
+use crate::backend::{FilterError, FilterSpec, MedianBackend};
+use crate::color;
+use crate::region;
+use crate::sample::Sample;
+use crate::shared::{filter_image_rows, map_any_image, AnyImage, Image, Plane};
+use crate::switching;
 use rayon::prelude::*;
 
 /// Apply median filter in parallel using Rayon
 /// 
 /// # Arguments
 /// * `img` - Input image
-/// * `kernel_size` - Size of the kernel (3 or 5)
+/// * `kernel_size` - Size of the kernel (odd, at most `shared::MAX_KERNEL_SIZE`)
 /// 
 /// # Returns
 /// Filtered image
-pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
-    let mut output = Image::new_empty(img.width, img.height);
+pub fn apply_median_filter<T: Sample>(img: &Image<T>, kernel_size: usize) -> Image<T> {
+    apply_filter(img, &FilterSpec::new(kernel_size))
+}
+
+/// Apply the median filter described by `spec` in parallel using Rayon
+pub fn apply_filter<T: Sample>(img: &Image<T>, spec: &FilterSpec) -> Image<T> {
+    let planes: Vec<Plane<T>> = (0..img.channels()).map(|channel| img.plane(channel)).collect();
 
-    // Process rows in parallel
-    let rows: Vec<_> = (0..img.height)
+    // Process bands of rows in parallel, all channels of a band in one task.
+    // One band per thread keeps the histogram setup of the constant-time
+    // algorithm to a minimum.
+    let band_height = img.height.div_ceil(rayon::current_num_threads() as u32).max(1);
+    let starts: Vec<u32> = (0..img.height).step_by(band_height as usize).collect();
+    let bands: Vec<Vec<Vec<T>>> = starts
         .into_par_iter()
-        .map(|y| {
-            let mut row_pixels = Vec::with_capacity(img.width as usize);
-            for x in 0..img.width {
-                let neighborhood = collect_neighborhood(img, x, y, kernel_size);
-                let median_pixel = median_rgb(&neighborhood);
-                row_pixels.push(median_pixel);
-            }
-            (y, row_pixels)
+        .map(|start| {
+            let end = (start + band_height).min(img.height);
+            filter_image_rows(&planes, img.color, spec, start..end)
         })
         .collect();
 
-    // Write results back to output image
-    for (y, row_pixels) in rows {
-        for (x, pixel) in row_pixels.into_iter().enumerate() {
-            output.put_pixel(x as u32, y, pixel);
-        }
-    }
+    // Concatenate the bands of every channel in order
+    let output = (0..planes.len())
+        .map(|channel| Plane {
+            data: bands.iter().flat_map(|band| band[channel].iter().copied()).collect(),
+            width: img.width,
+            height: img.height,
+        })
+        .collect();
 
-    output
+    Image::from_planes(output, img.color)
 }
 
+/// Parallel (Rayon) median filter backend
+pub struct ParallelBackend;
+
+impl MedianBackend for ParallelBackend {
+    fn name(&self) -> &'static str {
+        "par"
+    }
+
+    fn filter(&self, img: &AnyImage, spec: &FilterSpec) -> Result<AnyImage, FilterError> {
+        spec.validate(self.max_kernel_size())?;
+        spec.validate_samples(img.sample_type())?;
+        spec.validate_color(img.color())?;
+        switching::filter_switched(img, spec, |img, spec| {
+            region::filter_in_region(img, spec, |img| {
+                color::filter_in_color_space(img, spec, |img| {
+                    Ok(map_any_image!(img, img => apply_filter(img, spec)))
+                })
+            })
+        })
+    }
+}
@@ -0,0 +1,300 @@
+//! Region-of-interest filtering.
+//!
+//! A region limits filtering to part of the image, given as a rectangle or a
+//! mask image: pixels inside it are filtered, all others keep their input
+//! value. Pixels outside the region still take part in the windows of the
+//! pixels inside, as they would when filtering the whole image.
+//!
+//! Backends filter only the region's bounding box plus the rows and columns
+//! its windows read, and copy the filtered region pixels into the input.
+
+use crate::backend::{FilterError, FilterSpec};
+use crate::sample::Sample;
+use crate::shared::{map_any_image, AnyImage, BorderMode, Image};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Rectangle of pixels, `width` x `height` with its top left corner at (x, y)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// Whether (x, y) lies inside the rectangle
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x.checked_sub(self.x).is_some_and(|dx| dx < self.width)
+            && y.checked_sub(self.y).is_some_and(|dy| dy < self.height)
+    }
+
+    /// Whether the rectangle lies inside a `width` x `height` image
+    fn fits(&self, width: u32, height: u32) -> bool {
+        self.x.checked_add(self.width).is_some_and(|right| right <= width)
+            && self.y.checked_add(self.height).is_some_and(|bottom| bottom <= height)
+    }
+
+    /// The rectangle grown by `dx` columns and `dy` rows on every side,
+    /// clipped to a `width` x `height` image
+    fn expand(&self, dx: u32, dy: u32, width: u32, height: u32) -> Rect {
+        let (x, y) = (self.x.saturating_sub(dx), self.y.saturating_sub(dy));
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width + dx).min(width) - x,
+            height: (self.y + self.height + dy).min(height) - y,
+        }
+    }
+}
+
+impl FromStr for Rect {
+    type Err = String;
+
+    /// Parse `x,y,width,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<u32> = s
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid rectangle '{}', expected x,y,width,height", s))?;
+
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Rect { x, y, width, height }),
+            [_, _, _, _] => Err(format!("Rectangle '{}' is empty", s)),
+            _ => Err(format!("Invalid rectangle '{}', expected x,y,width,height", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Pixels the filter is limited to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Region {
+    /// Every pixel of a rectangle
+    Rect(Rect),
+    /// Pixels set in a mask the size of the image, row by row
+    Mask {
+        width: u32,
+        height: u32,
+        pixels: Vec<bool>,
+    },
+}
+
+impl Region {
+    /// Load a mask image; pixels with a nonzero luma belong to the region
+    pub fn load_mask<P: AsRef<Path>>(path: P) -> Result<Self, FilterError> {
+        let path = path.as_ref();
+        let img = image::open(path)
+            .map_err(|e| FilterError::InvalidRegion(format!("{}: {}", path.display(), e)))?
+            .to_luma8();
+        let (width, height) = img.dimensions();
+
+        Ok(Region::Mask {
+            width,
+            height,
+            pixels: img.pixels().map(|p| p[0] > 0).collect(),
+        })
+    }
+
+    /// Whether pixel (x, y) belongs to the region
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        match self {
+            Region::Rect(rect) => rect.contains(x, y),
+            Region::Mask { width, pixels, .. } => pixels[y as usize * *width as usize + x as usize],
+        }
+    }
+
+    /// Membership of every pixel of a `width` x `height` image, row by row
+    pub fn pixels(&self, width: u32, height: u32) -> Vec<bool> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.contains(x, y))
+            .collect()
+    }
+
+    /// Smallest rectangle holding every pixel of the region
+    pub fn bounds(&self) -> Option<Rect> {
+        match self {
+            Region::Rect(rect) => Some(*rect),
+            Region::Mask { width, pixels, .. } => {
+                let width = *width as usize;
+                let mut set = pixels.iter().enumerate().filter(|&(_, &p)| p).map(|(i, _)| (i % width, i / width));
+                let (x, y) = set.next()?;
+                let (x0, y0, x1, y1) = set.fold((x, y, x, y), |(x0, y0, x1, y1), (x, y)| {
+                    (x0.min(x), y0.min(y), x1.max(x), y1.max(y))
+                });
+                Some(Rect {
+                    x: x0 as u32,
+                    y: y0 as u32,
+                    width: (x1 - x0 + 1) as u32,
+                    height: (y1 - y0 + 1) as u32,
+                })
+            }
+        }
+    }
+
+    /// Check that the region fits a `width` x `height` image and selects at
+    /// least one pixel
+    pub fn validate(&self, width: u32, height: u32) -> Result<(), FilterError> {
+        match self {
+            Region::Rect(rect) if !rect.fits(width, height) => {
+                Err(FilterError::InvalidRegion(format!(
+                    "rectangle {} exceeds the {}x{} image",
+                    rect, width, height
+                )))
+            }
+            Region::Mask { width: w, height: h, .. } if (*w, *h) != (width, height) => {
+                Err(FilterError::InvalidRegion(format!(
+                    "the {}x{} mask does not match the {}x{} image",
+                    w, h, width, height
+                )))
+            }
+            _ if self.bounds().is_none() => {
+                Err(FilterError::InvalidRegion("the mask selects no pixels".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copy of the `rect` part of `img`
+fn crop<T: Sample>(img: &Image<T>, rect: Rect) -> Image<T> {
+    let channels = img.channels();
+    let row_len = rect.width as usize * channels;
+    let mut data = Vec::with_capacity(row_len * rect.height as usize);
+
+    for y in rect.y..rect.y + rect.height {
+        let start = (y as usize * img.width as usize + rect.x as usize) * channels;
+        data.extend_from_slice(&img.data[start..start + row_len]);
+    }
+
+    Image {
+        data,
+        width: rect.width,
+        height: rect.height,
+        color: img.color,
+    }
+}
+
+/// `img` with the pixels of `region` taken from `filtered`, which covers
+/// the `area` part of `img`
+fn paste<T: Sample>(img: &Image<T>, filtered: &Image<T>, area: Rect, region: &Region) -> Image<T> {
+    let mut output = img.clone();
+    for y in area.y..area.y + area.height {
+        for x in (area.x..area.x + area.width).filter(|&x| region.contains(x, y)) {
+            output.put_pixel(x, y, filtered.get_pixel(x - area.x, y - area.y));
+        }
+    }
+    output
+}
+
+/// Run `filter` on the part of `img` the spec's region needs and keep the
+/// result only inside the region
+///
+/// Images are passed through whole when the spec has no region. Wrapping
+/// borders read pixels from the opposite edge, so with `BorderMode::Wrap`
+/// the whole image is filtered.
+pub fn filter_in_region<F>(img: &AnyImage, spec: &FilterSpec, filter: F) -> Result<AnyImage, FilterError>
+where
+    F: FnOnce(&AnyImage) -> Result<AnyImage, FilterError>,
+{
+    let Some(region) = &spec.region else {
+        return filter(img);
+    };
+    region.validate(img.width(), img.height())?;
+
+    let (width, height) = (img.width(), img.height());
+    let full = Rect { x: 0, y: 0, width, height };
+    let area = match (spec.border, region.bounds()) {
+        (BorderMode::Wrap, _) | (_, None) => full,
+        (_, Some(bounds)) => bounds.expand(spec.horizontal_reach() as u32, spec.reach() as u32, width, height),
+    };
+
+    let cropped = map_any_image!(img, img => crop(img, area));
+    let filtered = filter(&cropped)?;
+    Ok(match (img, &filtered) {
+        (AnyImage::U8(i), AnyImage::U8(f)) => AnyImage::U8(paste(i, f, area, region)),
+        (AnyImage::U16(i), AnyImage::U16(f)) => AnyImage::U16(paste(i, f, area, region)),
+        (AnyImage::F32(i), AnyImage::F32(f)) => AnyImage::F32(paste(i, f, area, region)),
+        _ => unreachable!("filtering keeps the sample type"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::create_backend;
+    use crate::test_util::impulse_image;
+
+    /// Samples as ordering keys, which keep every bit of float samples
+    fn keys(img: &AnyImage) -> Vec<u32> {
+        match img {
+            AnyImage::U8(img) => img.data.iter().map(|v| v.to_key()).collect(),
+            AnyImage::U16(img) => img.data.iter().map(|v| v.to_key()).collect(),
+            AnyImage::F32(img) => img.data.iter().map(|v| v.to_key()).collect(),
+        }
+    }
+
+    #[test]
+    fn rectangles_past_the_largest_coordinate_are_rejected() {
+        for rect in ["4294967295,0,10,10", "0,4294967295,10,10", "10,0,4294967295,1"] {
+            let region = Region::Rect(rect.parse().unwrap());
+            assert!(matches!(region.validate(20, 20), Err(FilterError::InvalidRegion(_))), "{}", rect);
+        }
+    }
+
+    /// Every backend leaves the pixels outside the region bit for bit as
+    /// they were and filters the pixels inside like the whole image
+    #[test]
+    fn pixels_outside_the_region_are_unchanged() {
+        let noisy = impulse_image(17, 11);
+        let floats = Image {
+            data: noisy.data.iter().map(|&v| v as f32 / 255.0).collect(),
+            width: noisy.width,
+            height: noisy.height,
+            color: noisy.color,
+        };
+        let scattered = (0..17 * 11).map(|i| i % 7 == 0 || i % 11 == 3).collect();
+        let regions = [
+            Region::Rect(Rect { x: 3, y: 2, width: 5, height: 4 }),
+            Region::Mask { width: 17, height: 11, pixels: scattered },
+        ];
+
+        for img in [AnyImage::U8(noisy), AnyImage::F32(floats)] {
+            for name in ["seq", "par", "gpu"] {
+                let backend = create_backend(name).unwrap();
+                let whole = match backend.filter(&img, &FilterSpec::new(3)) {
+                    Ok(whole) => whole,
+                    Err(FilterError::Gpu(msg)) if msg.starts_with("Failed to find GPU adapter") => continue,
+                    Err(e) => panic!("{} backend failed: {}", name, e),
+                };
+
+                for region in &regions {
+                    let spec = FilterSpec { region: Some(region.clone()), ..FilterSpec::new(3) };
+                    let filtered = backend.filter(&img, &spec).unwrap();
+                    let (input, output, expected) = (keys(&img), keys(&filtered), keys(&whole));
+                    for (i, inside) in region.pixels(17, 11).into_iter().enumerate() {
+                        let reference = if inside { &expected } else { &input };
+                        assert_eq!(
+                            output[i * 3..i * 3 + 3],
+                            reference[i * 3..i * 3 + 3],
+                            "{} {} at pixel {}, inside: {}",
+                            name,
+                            img.sample_type(),
+                            i,
+                            inside
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
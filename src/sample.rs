@@ -0,0 +1,193 @@
+//! Sample types an image can hold.
+//!
+//! Images store 8-bit, 16-bit or 32-bit float channel samples. Float images
+//! use the 0.0..=1.0 range for black to white but may exceed it (HDR).
+
+use crate::shared::ColorType;
+use image::ExtendedColorType;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Type of the channel samples of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    U8,
+    U16,
+    F32,
+}
+
+impl SampleType {
+    /// Number of significant bits in the sample's ordering key
+    pub fn bits(self) -> u32 {
+        match self {
+            SampleType::U8 => 8,
+            SampleType::U16 => 16,
+            SampleType::F32 => 32,
+        }
+    }
+}
+
+impl fmt::Display for SampleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleType::U8 => write!(f, "u8"),
+            SampleType::U16 => write!(f, "u16"),
+            SampleType::F32 => write!(f, "f32"),
+        }
+    }
+}
+
+/// Channel sample of an image
+pub trait Sample: bytemuck::Pod + PartialOrd + Default + Send + Sync + fmt::Debug + 'static {
+    /// Runtime tag of the type
+    const TYPE: SampleType;
+
+    /// White; the peak signal value used by PSNR
+    const MAX: Self;
+
+    /// Convert to `f64` without rounding
+    fn to_f64(self) -> f64;
+
+    /// Nearest sample to `value`; integer types round and clamp to `0..=MAX`
+    fn from_f64(value: f64) -> Self;
+
+    /// Scale an 8-bit value, such as a constant border, to this type's range
+    fn from_u8(value: u8) -> Self;
+
+    /// Total order used to select medians (for floats, NaN sorts above
+    /// every number)
+    fn total_cmp(&self, other: &Self) -> Ordering;
+
+    /// Key whose unsigned order matches `total_cmp`, using the low
+    /// `TYPE.bits()` bits
+    fn to_key(self) -> u32;
+
+    /// Inverse of `to_key`
+    fn from_key(key: u32) -> Self;
+
+    /// Color type the image crate uses for `color` images of this sample type
+    fn extended_color(color: ColorType) -> ExtendedColorType;
+}
+
+impl Sample for u8 {
+    const TYPE: SampleType = SampleType::U8;
+    const MAX: Self = u8::MAX;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, u8::MAX as f64) as u8
+    }
+
+    fn from_u8(value: u8) -> Self {
+        value
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+
+    fn to_key(self) -> u32 {
+        self as u32
+    }
+
+    fn from_key(key: u32) -> Self {
+        key as u8
+    }
+
+    fn extended_color(color: ColorType) -> ExtendedColorType {
+        match color {
+            ColorType::Gray => ExtendedColorType::L8,
+            ColorType::GrayAlpha => ExtendedColorType::La8,
+            ColorType::Rgb => ExtendedColorType::Rgb8,
+            ColorType::Rgba => ExtendedColorType::Rgba8,
+        }
+    }
+}
+
+impl Sample for u16 {
+    const TYPE: SampleType = SampleType::U16;
+    const MAX: Self = u16::MAX;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.round().clamp(0.0, u16::MAX as f64) as u16
+    }
+
+    fn from_u8(value: u8) -> Self {
+        // 0xFF maps to 0xFFFF
+        value as u16 * 257
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+
+    fn to_key(self) -> u32 {
+        self as u32
+    }
+
+    fn from_key(key: u32) -> Self {
+        key as u16
+    }
+
+    fn extended_color(color: ColorType) -> ExtendedColorType {
+        match color {
+            ColorType::Gray => ExtendedColorType::L16,
+            ColorType::GrayAlpha => ExtendedColorType::La16,
+            ColorType::Rgb => ExtendedColorType::Rgb16,
+            ColorType::Rgba => ExtendedColorType::Rgba16,
+        }
+    }
+}
+
+impl Sample for f32 {
+    const TYPE: SampleType = SampleType::F32;
+    const MAX: Self = 1.0;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn from_u8(value: u8) -> Self {
+        value as f32 / 255.0
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        f32::total_cmp(self, other)
+    }
+
+    fn to_key(self) -> u32 {
+        // Flip negative numbers entirely and set the sign bit of positive
+        // ones, the same order as `f32::total_cmp`
+        let bits = self.to_bits();
+        if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits | 1 << 31
+        }
+    }
+
+    fn from_key(key: u32) -> Self {
+        let bits = if key >> 31 == 1 { key & !(1 << 31) } else { !key };
+        f32::from_bits(bits)
+    }
+
+    fn extended_color(color: ColorType) -> ExtendedColorType {
+        match color {
+            // The image crate has no grayscale float types; `Image::save`
+            // expands grayscale float images to RGB(A) first
+            ColorType::Gray | ColorType::Rgb => ExtendedColorType::Rgb32F,
+            ColorType::GrayAlpha | ColorType::Rgba => ExtendedColorType::Rgba32F,
+        }
+    }
+}
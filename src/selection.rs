@@ -0,0 +1,360 @@
+//! Allocation-free median selection.
+//!
+//! `select_median` returns the same value as `shared::median` (index
+//! `len / 2` of the sorted values) without fully sorting: 9 and 25 values,
+//! the 3x3 and 5x5 windows, go through fixed comparator networks and any
+//! other length through quickselect. `select_rank` extends this to the other
+//! rank filters.
+
+use crate::kernel::Kernel;
+use crate::sample::Sample;
+use crate::shared::{BorderMode, Plane, RankFilter};
+
+/// Order `p[a]` and `p[b]` so that `p[a] <= p[b]`
+#[inline(always)]
+fn sort_pair<T: Sample>(p: &mut [T], a: usize, b: usize) {
+    let (x, y) = (p[a], p[b]);
+    let swap = y.total_cmp(&x).is_lt();
+    p[a] = if swap { y } else { x };
+    p[b] = if swap { x } else { y };
+}
+
+/// Comparator pairs selecting the median of 9 values into index 4
+/// (19 comparisons, Paeth / Smith); each pair `(a, b)` leaves the smaller
+/// value at `a`
+#[rustfmt::skip]
+pub(crate) const MEDIAN_9: [(usize, usize); 19] = [
+    (1, 2), (4, 5), (7, 8), (0, 1), (3, 4), (6, 7), (1, 2), (4, 5), (7, 8),
+    (0, 3), (5, 8), (4, 7), (3, 6), (1, 4), (2, 5), (4, 7), (4, 2), (6, 4), (4, 2),
+];
+
+/// Comparator pairs selecting the median of 25 values into index 12
+/// (99 comparisons, Devillard)
+#[rustfmt::skip]
+pub(crate) const MEDIAN_25: [(usize, usize); 99] = [
+    (0, 1), (3, 4), (2, 4), (2, 3), (6, 7), (5, 7), (5, 6), (9, 10), (8, 10),
+    (8, 9), (12, 13), (11, 13), (11, 12), (15, 16), (14, 16), (14, 15), (18, 19), (17, 19),
+    (17, 18), (21, 22), (20, 22), (20, 21), (23, 24), (2, 5), (3, 6), (0, 6), (0, 3),
+    (4, 7), (1, 7), (1, 4), (11, 14), (8, 14), (8, 11), (12, 15), (9, 15), (9, 12),
+    (13, 16), (10, 16), (10, 13), (20, 23), (17, 23), (17, 20), (21, 24), (18, 24), (18, 21),
+    (19, 22), (8, 17), (9, 18), (0, 18), (0, 9), (10, 19), (1, 19), (1, 10), (11, 20),
+    (2, 20), (2, 11), (12, 21), (3, 21), (3, 12), (13, 22), (4, 22), (4, 13), (14, 23),
+    (5, 23), (5, 14), (15, 24), (6, 24), (6, 15), (7, 16), (7, 19), (13, 21), (15, 23),
+    (7, 13), (7, 15), (1, 9), (3, 11), (5, 17), (11, 17), (9, 17), (4, 10), (6, 12),
+    (7, 14), (4, 6), (4, 7), (12, 14), (10, 14), (6, 7), (10, 12), (6, 10), (6, 17),
+    (12, 17), (7, 17), (7, 10), (12, 18), (7, 12), (10, 18), (12, 20), (10, 20), (10, 12),
+];
+
+/// Median of exactly 9 values; reorders `p`
+pub fn median9<T: Sample>(p: &mut [T; 9]) -> T {
+    for &(a, b) in &MEDIAN_9 {
+        sort_pair(p, a, b);
+    }
+    p[4]
+}
+
+/// Median of exactly 25 values; reorders `p`
+pub fn median25<T: Sample>(p: &mut [T; 25]) -> T {
+    for &(a, b) in &MEDIAN_25 {
+        sort_pair(p, a, b);
+    }
+    p[12]
+}
+
+/// Median of `values` (index `len / 2` once sorted); reorders `values`
+///
+/// Bit-identical to `shared::median`, but never sorts more than needed.
+pub fn select_median<T: Sample>(values: &mut [T]) -> T {
+    match values.len() {
+        9 => median9(values.try_into().unwrap()),
+        25 => median25(values.try_into().unwrap()),
+        len => *values.select_nth_unstable_by(len / 2, T::total_cmp).1,
+    }
+}
+
+/// Value `filter` outputs for `values`; reorders `values`
+///
+/// Ranks that fall on the median use `select_median`, so the median filter
+/// keeps its comparator networks.
+pub fn select_rank<T: Sample>(values: &mut [T], filter: RankFilter) -> T {
+    let len = values.len();
+    match filter.rank(len as u64) {
+        Some(rank) if rank as usize == len / 2 => select_median(values),
+        Some(rank) => *values.select_nth_unstable_by(rank as usize, T::total_cmp).1,
+        None => {
+            values.sort_unstable_by(T::total_cmp);
+            let runs = values.iter().map(|&value| (value, 1));
+            trimmed_mean(runs, len as u64, filter.trimmed(len as u64))
+        }
+    }
+}
+
+/// Value `filter` outputs for `(value, weight)` pairs, each value repeated
+/// `weight` times; reorders `pairs`
+///
+/// The total weight must be positive.
+pub fn weighted_rank<T: Sample>(pairs: &mut [(T, u32)], filter: RankFilter) -> T {
+    pairs.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    let total: u64 = pairs.iter().map(|&(_, w)| w as u64).sum();
+
+    let Some(mut rank) = filter.rank(total) else {
+        let runs = pairs.iter().map(|&(value, weight)| (value, weight as u64));
+        return trimmed_mean(runs, total, filter.trimmed(total));
+    };
+    for &(value, weight) in pairs.iter() {
+        if rank < weight as u64 {
+            return value;
+        }
+        rank -= weight as u64;
+    }
+    unreachable!("the total weight is positive")
+}
+
+/// Mean of the `count` sorted samples without the `trim` smallest and
+/// largest, given in ascending order as runs of equal values and their
+/// lengths
+///
+/// Integer samples round the mean to the nearest value.
+pub fn trimmed_mean<T: Sample>(runs: impl IntoIterator<Item = (T, u64)>, count: u64, trim: u64) -> T {
+    let (start, end) = (trim, count - trim);
+    let mut index = 0;
+    let mut sum = 0.0;
+
+    for (value, len) in runs {
+        let kept = (index + len).min(end).saturating_sub(index.max(start));
+        sum += value.to_f64() * kept as f64;
+        index += len;
+        if index >= end {
+            break;
+        }
+    }
+
+    T::from_f64(sum / (end - start) as f64)
+}
+
+/// Reusable sample buffer for computing window medians pixel by pixel
+/// without allocating
+///
+/// The `*_median_at` methods output the buffer's rank filter, the median
+/// unless created with `with_filter`.
+pub struct WindowSamples<T: Sample = u8> {
+    values: Vec<T>,
+    weighted: Vec<(T, u32)>,
+    filter: RankFilter,
+}
+
+impl<T: Sample> WindowSamples<T> {
+    /// Create a buffer large enough for every window of `kernel`
+    pub fn new(kernel: &Kernel) -> Self {
+        WindowSamples::with_filter(kernel, RankFilter::Median)
+    }
+
+    /// Create a buffer for every window of `kernel` that outputs `filter`
+    /// instead of the median
+    pub fn with_filter(kernel: &Kernel, filter: RankFilter) -> Self {
+        WindowSamples {
+            values: Vec::with_capacity(kernel.len()),
+            weighted: Vec::with_capacity(kernel.len()),
+            filter,
+        }
+    }
+
+    /// Median of the window of `kernel` centered at (x, y)
+    ///
+    /// Same result as `median` over `collect_neighborhood`, including the
+    /// fallback to the center sample when shrink leaves no samples.
+    pub fn median_at(
+        &mut self,
+        plane: &Plane<T>,
+        x: u32,
+        y: u32,
+        kernel: &Kernel,
+        border: BorderMode,
+    ) -> T {
+        self.values.clear();
+        self.values.extend(
+            kernel
+                .offsets()
+                .iter()
+                .filter_map(|&(dx, dy)| plane.get_padded(x as i32 + dx, y as i32 + dy, border)),
+        );
+
+        self.select(plane, x, y)
+    }
+
+    /// Median of the window samples whose pixel has an alpha of at least
+    /// `threshold`
+    ///
+    /// `alpha` is padded like `plane`; when no sample is opaque enough the
+    /// center sample is kept.
+    #[allow(clippy::too_many_arguments)]
+    pub fn opaque_median_at(
+        &mut self,
+        plane: &Plane<T>,
+        alpha: &Plane<T>,
+        threshold: T,
+        x: u32,
+        y: u32,
+        kernel: &Kernel,
+        border: BorderMode,
+    ) -> T {
+        self.values.clear();
+        self.values.extend(kernel.offsets().iter().filter_map(|&(dx, dy)| {
+            let (px, py) = (x as i32 + dx, y as i32 + dy);
+            if alpha.get_padded(px, py, border)?.total_cmp(&threshold).is_lt() {
+                return None;
+            }
+            plane.get_padded(px, py, border)
+        }));
+
+        self.select(plane, x, y)
+    }
+
+    /// Weighted median of the window, each sample counting `weights[i]`
+    /// times for the `i`-th kernel offset
+    ///
+    /// With `opaque` set to `(alpha, threshold)` only samples whose pixel has
+    /// an alpha of at least `threshold` take part. When no sample with a
+    /// positive weight is left the center sample is kept.
+    #[allow(clippy::too_many_arguments)]
+    pub fn weighted_median_at(
+        &mut self,
+        plane: &Plane<T>,
+        opaque: Option<(&Plane<T>, T)>,
+        x: u32,
+        y: u32,
+        kernel: &Kernel,
+        weights: &[u32],
+        border: BorderMode,
+    ) -> T {
+        self.weighted.clear();
+        let window = kernel.offsets().iter().zip(weights).filter(|&(_, &w)| w > 0);
+        self.weighted.extend(window.filter_map(|(&(dx, dy), &weight)| {
+            let (px, py) = (x as i32 + dx, y as i32 + dy);
+            if let Some((alpha, threshold)) = opaque {
+                if alpha.get_padded(px, py, border)?.total_cmp(&threshold).is_lt() {
+                    return None;
+                }
+            }
+            Some((plane.get_padded(px, py, border)?, weight))
+        }));
+
+        if self.weighted.is_empty() {
+            return plane.get(x, y);
+        }
+
+        weighted_rank(&mut self.weighted, self.filter)
+    }
+
+    /// Rank filter value of the collected samples, or the center sample if
+    /// there are none
+    fn select(&mut self, plane: &Plane<T>, x: u32, y: u32) -> T {
+        if self.values.is_empty() {
+            return plane.get(x, y);
+        }
+
+        select_rank(&mut self.values, self.filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::median;
+    use crate::test_util::XorShift;
+
+    /// `count` windows of `len` values drawn from `values`, generated with a
+    /// fixed xorshift sequence; the few distinct values give many duplicates
+    fn windows<T: Sample>(len: usize, count: usize, values: &[T]) -> Vec<Vec<T>> {
+        let mut rng = XorShift::new(0x9e37_79b9);
+        (0..count)
+            .map(|_| (0..len).map(|_| values[rng.next_u32() as usize % values.len()]).collect())
+            .collect()
+    }
+
+    /// Sample sets covering the extremes of each type and, for floats,
+    /// both zeros, which compare equal but must keep their sign
+    fn check_all(check: impl Fn(&[u8], &[u16], &[f32])) {
+        check(
+            &[0, 1, 7, 128, 200, 254, 255],
+            &[0, 1, 255, 256, 40000, 65535],
+            &[-0.0, 0.0, 0.25, 0.5, 1.0, -0.5],
+        );
+        check(&[3, 3, 3, 9], &[7, 7, 8], &[0.0, -0.0]);
+    }
+
+    fn assert_same<T: Sample>(actual: T, expected: T, values: &[T]) {
+        assert_eq!(actual.to_key(), expected.to_key(), "{:?} from {:?}", actual, values);
+    }
+
+    #[test]
+    fn median9_matches_sort() {
+        fn check<T: Sample>(values: &[T]) {
+            for window in windows(9, 2000, values) {
+                let mut network: [T; 9] = window.clone().try_into().unwrap();
+                assert_same(median9(&mut network), median(&mut window.clone()), &window);
+            }
+        }
+        check_all(|a, b, c| {
+            check(a);
+            check(b);
+            check(c);
+        });
+    }
+
+    #[test]
+    fn median25_matches_sort() {
+        fn check<T: Sample>(values: &[T]) {
+            for window in windows(25, 2000, values) {
+                let mut network: [T; 25] = window.clone().try_into().unwrap();
+                assert_same(median25(&mut network), median(&mut window.clone()), &window);
+            }
+        }
+        check_all(|a, b, c| {
+            check(a);
+            check(b);
+            check(c);
+        });
+    }
+
+    #[test]
+    fn select_rank_matches_sort() {
+        fn check<T: Sample>(values: &[T]) {
+            let filters = [
+                RankFilter::Median,
+                RankFilter::Min,
+                RankFilter::Max,
+                RankFilter::Percentile(2500),
+                RankFilter::Percentile(9000),
+                RankFilter::TrimmedMean(0),
+                RankFilter::TrimmedMean(2000),
+                RankFilter::TrimmedMean(5000),
+            ];
+            for len in [1, 2, 5, 9, 13, 24, 25, 49] {
+                for window in windows(len, 200, values) {
+                    let mut sorted = window.clone();
+                    sorted.sort_unstable_by(T::total_cmp);
+
+                    for filter in filters {
+                        let expected = match filter.rank(len as u64) {
+                            Some(rank) => sorted[rank as usize],
+                            None => {
+                                let trim = filter.trimmed(len as u64) as usize;
+                                let kept = &sorted[trim..len - trim];
+                                let sum = kept.iter().fold(0.0, |sum, v| sum + v.to_f64());
+                                T::from_f64(sum / kept.len() as f64)
+                            }
+                        };
+                        assert_same(select_rank(&mut window.clone(), filter), expected, &window);
+                    }
+                    assert_same(select_median(&mut window.clone()), median(&mut window.clone()), &window);
+                }
+            }
+        }
+        check_all(|a, b, c| {
+            check(a);
+            check(b);
+            check(c);
+        });
+    }
+}
@@ -1,24 +1,56 @@
-use crate::shared::{collect_neighborhood, median_rgb, Image};
+use crate::backend::{FilterError, FilterSpec, MedianBackend};
+use crate::color;
+use crate::region;
+use crate::sample::Sample;
+use crate::shared::{filter_image_rows, map_any_image, AnyImage, Image, Plane};
+use crate::switching;
 
 /// Apply median filter sequentially
 /// 
 /// # Arguments
 /// * `img` - Input image
-/// * `kernel_size` - Size of the kernel (3 or 5)
+/// * `kernel_size` - Size of the kernel (odd, at most `shared::MAX_KERNEL_SIZE`)
 /// 
 /// # Returns
 /// Filtered image
-pub fn apply_median_filter(img: &Image, kernel_size: usize) -> Image {
-    let mut output = Image::new_empty(img.width, img.height);
+pub fn apply_median_filter<T: Sample>(img: &Image<T>, kernel_size: usize) -> Image<T> {
+    apply_filter(img, &FilterSpec::new(kernel_size))
+}
 
-    for y in 0..img.height {
-        for x in 0..img.width {
-            let neighborhood = collect_neighborhood(img, x, y, kernel_size);
-            let median_pixel = median_rgb(&neighborhood);
-            output.put_pixel(x, y, median_pixel);
-        }
-    }
+/// Apply the median filter described by `spec` sequentially, one channel
+/// after another
+pub fn apply_filter<T: Sample>(img: &Image<T>, spec: &FilterSpec) -> Image<T> {
+    let planes: Vec<Plane<T>> = (0..img.channels()).map(|channel| img.plane(channel)).collect();
+    let output = filter_image_rows(&planes, img.color, spec, 0..img.height)
+        .into_iter()
+        .map(|data| Plane {
+            data,
+            width: img.width,
+            height: img.height,
+        })
+        .collect();
 
-    output
+    Image::from_planes(output, img.color)
 }
 
+/// Sequential median filter backend
+pub struct SequentialBackend;
+
+impl MedianBackend for SequentialBackend {
+    fn name(&self) -> &'static str {
+        "seq"
+    }
+
+    fn filter(&self, img: &AnyImage, spec: &FilterSpec) -> Result<AnyImage, FilterError> {
+        spec.validate(self.max_kernel_size())?;
+        spec.validate_samples(img.sample_type())?;
+        spec.validate_color(img.color())?;
+        switching::filter_switched(img, spec, |img, spec| {
+            region::filter_in_region(img, spec, |img| {
+                color::filter_in_color_space(img, spec, |img| {
+                    Ok(map_any_image!(img, img => apply_filter(img, spec)))
+                })
+            })
+        })
+    }
+}
@@ -1,173 +1,1067 @@
-use image::{Rgb, RgbImage};
-use rand::Rng;
+use crate::adaptive;
+use crate::backend::FilterSpec;
+use crate::color;
+use crate::histogram;
+use crate::simd;
+use crate::kernel::Kernel;
+use crate::noise::{self, NoiseSpec};
+use crate::sample::{Sample, SampleType};
+use crate::selection::WindowSamples;
+use crate::vector;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::any::Any;
+use std::fmt;
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
+
+/// Largest kernel size accepted by the CPU implementations
+///
+/// Selection-based medians cost O(k²) per pixel, so windows above this are
+/// impractically slow rather than incorrect.
+pub const MAX_KERNEL_SIZE: usize = 127;
+
+/// Kernels wider or taller than this use the constant-time median when the
+/// algorithm is left on `Algorithm::Auto`; smaller ones use Huang's, which
+/// measured faster up to 7x7 on 8-bit images
+pub const CONSTANT_TIME_THRESHOLD: usize = 7;
+
+/// Method used by the CPU backends to find the median of each window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    /// Fastest algorithm for the kernel, filter and sample type: SIMD for
+    /// 3x3 and 5x5 medians, Huang's up to `CONSTANT_TIME_THRESHOLD` and for
+    /// non-rectangular kernels, constant-time above; sorting for 16-bit and
+    /// float samples and small trimmed means
+    #[default]
+    Auto,
+    /// Select the median from the window samples of every pixel (sorting
+    /// networks for 3x3 and 5x5, quickselect otherwise)
+    Sort,
+    /// Perreault–Hébert column histograms (rectangular kernels only)
+    ConstantTime,
+    /// Huang's histogram sliding along each row
+    Huang,
+    /// Sorting networks applied to many pixels at once with SIMD
+    /// instructions (3x3 and 5x5 square kernels only)
+    Simd,
+}
+
+impl Algorithm {
+    /// Algorithm actually used for `filter` over `kernel` on `sample`
+    /// images, resolving `Auto`
+    ///
+    /// The histogram algorithms scan all 256 bins for the trimmed mean, which
+    /// sorting beats on small windows.
+    pub fn for_kernel(self, kernel: &Kernel, filter: RankFilter, sample: SampleType) -> Algorithm {
+        if self != Algorithm::Auto {
+            return self;
+        }
+
+        let large = kernel.width().max(kernel.height()) > CONSTANT_TIME_THRESHOLD;
+        let trimmed_mean = matches!(filter, RankFilter::TrimmedMean(_));
+        if !Algorithm::Huang.supports_samples(sample) || (trimmed_mean && !large) {
+            Algorithm::Sort
+        } else if filter == RankFilter::Median && Algorithm::Simd.supports(kernel) {
+            Algorithm::Simd
+        } else if large && kernel.is_full() && !trimmed_mean {
+            Algorithm::ConstantTime
+        } else {
+            Algorithm::Huang
+        }
+    }
+
+    /// Whether the algorithm can run on `kernel`
+    pub fn supports(self, kernel: &Kernel) -> bool {
+        match self {
+            Algorithm::Auto | Algorithm::Sort | Algorithm::Huang => true,
+            Algorithm::ConstantTime => kernel.is_full(),
+            Algorithm::Simd => simd::supports(kernel),
+        }
+    }
+
+    /// Whether the algorithm can filter images of `sample` type
+    ///
+    /// The histogram and SIMD algorithms are specific to 8-bit samples.
+    pub fn supports_samples(self, sample: SampleType) -> bool {
+        match self {
+            Algorithm::Auto | Algorithm::Sort => true,
+            Algorithm::ConstantTime | Algorithm::Huang | Algorithm::Simd => sample == SampleType::U8,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Algorithm::Auto),
+            "sort" => Ok(Algorithm::Sort),
+            "constant-time" => Ok(Algorithm::ConstantTime),
+            "huang" => Ok(Algorithm::Huang),
+            "simd" => Ok(Algorithm::Simd),
+            _ => Err(format!(
+                "Unknown algorithm '{}'. Available: auto, sort, constant-time, huang, simd",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Algorithm::Auto => "auto",
+            Algorithm::Sort => "sort",
+            Algorithm::ConstantTime => "constant-time",
+            Algorithm::Huang => "huang",
+            Algorithm::Simd => "simd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How pixels outside the image are treated when the window crosses its border
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BorderMode {
+    /// Mirror without repeating the edge pixel (`dcb|abcd|cba`)
+    #[default]
+    Reflect101,
+    /// Mirror repeating the edge pixel (`cba|abcd|dcb`)
+    Reflect,
+    /// Repeat the edge pixel (`aaa|abcd|ddd`)
+    Replicate,
+    /// Continue from the opposite edge (`bcd|abcd|abc`)
+    Wrap,
+    /// Use a constant value for every channel (`vvv|abcd|vvv`), given on
+    /// the 8-bit scale and scaled to the image's sample type
+    Constant(u8),
+    /// Ignore out-of-bounds pixels, taking the median of fewer values
+    Shrink,
+}
+
+impl BorderMode {
+    /// Map a possibly out-of-bounds coordinate into `0..size`
+    ///
+    /// Returns `None` when the mode does not map the coordinate to an image
+    /// pixel (`Constant` and `Shrink` outside the image).
+    pub fn resolve(self, coord: i32, size: u32) -> Option<u32> {
+        let size = size as i64;
+        let c = coord as i64;
+        if (0..size).contains(&c) {
+            return Some(c as u32);
+        }
+
+        let resolved = match self {
+            BorderMode::Reflect101 => {
+                if size == 1 {
+                    0
+                } else {
+                    let period = 2 * (size - 1);
+                    let m = c.rem_euclid(period);
+                    if m < size { m } else { period - m }
+                }
+            }
+            BorderMode::Reflect => {
+                let period = 2 * size;
+                let m = c.rem_euclid(period);
+                if m < size { m } else { period - 1 - m }
+            }
+            BorderMode::Replicate => c.clamp(0, size - 1),
+            BorderMode::Wrap => c.rem_euclid(size),
+            BorderMode::Constant(_) | BorderMode::Shrink => return None,
+        };
+
+        Some(resolved as u32)
+    }
+}
+
+impl FromStr for BorderMode {
+    type Err = String;
+
+    /// Parse a mode name; `constant` may carry a value as `constant:<0-255>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reflect101" => Ok(BorderMode::Reflect101),
+            "reflect" => Ok(BorderMode::Reflect),
+            "replicate" => Ok(BorderMode::Replicate),
+            "wrap" => Ok(BorderMode::Wrap),
+            "constant" => Ok(BorderMode::Constant(0)),
+            "shrink" => Ok(BorderMode::Shrink),
+            _ => match s.strip_prefix("constant:") {
+                Some(value) => value
+                    .parse()
+                    .map(BorderMode::Constant)
+                    .map_err(|_| format!("Invalid constant border value '{}'", value)),
+                None => Err(format!(
+                    "Unknown border mode '{}'. Available: reflect101, reflect, replicate, wrap, constant[:value], shrink",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BorderMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BorderMode::Reflect101 => write!(f, "reflect101"),
+            BorderMode::Reflect => write!(f, "reflect"),
+            BorderMode::Replicate => write!(f, "replicate"),
+            BorderMode::Wrap => write!(f, "wrap"),
+            BorderMode::Constant(value) => write!(f, "constant:{}", value),
+            BorderMode::Shrink => write!(f, "shrink"),
+        }
+    }
+}
+
+/// Distance between pixels used by the vector median
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorNorm {
+    /// Sum of absolute channel differences
+    L1,
+    /// Euclidean distance
+    L2,
+}
+
+impl VectorNorm {
+    /// Distance between two pixels given as equally long sample slices
+    pub fn distance<T: Sample>(self, a: &[T], b: &[T]) -> f64 {
+        let diffs = a.iter().zip(b).map(|(&a, &b)| a.to_f64() - b.to_f64());
+        match self {
+            VectorNorm::L1 => diffs.map(f64::abs).sum(),
+            VectorNorm::L2 => diffs.map(|d| d * d).sum::<f64>().sqrt(),
+        }
+    }
+}
+
+impl FromStr for VectorNorm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l1" => Ok(VectorNorm::L1),
+            "l2" => Ok(VectorNorm::L2),
+            _ => Err(format!("Unknown vector norm '{}'. Available: l1, l2", s)),
+        }
+    }
+}
+
+impl fmt::Display for VectorNorm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorNorm::L1 => write!(f, "l1"),
+            VectorNorm::L2 => write!(f, "l2"),
+        }
+    }
+}
+
+/// Order statistic the filter outputs for each window
+///
+/// Ranks are computed in integers so that every backend picks the same
+/// sample: percentiles and trim fractions are kept in basis points
+/// (hundredths of a percent).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RankFilter {
+    /// Middle sample (index `n / 2` of the `n` sorted samples)
+    #[default]
+    Median,
+    /// Smallest sample (erosion)
+    Min,
+    /// Largest sample (dilation)
+    Max,
+    /// Sample at this percentile, in basis points (0 = min, 10000 = max),
+    /// rounded to the nearest index
+    Percentile(u32),
+    /// Mean of the samples left after dropping this fraction, in basis
+    /// points, from either end (at most 5000, which leaves the median)
+    TrimmedMean(u32),
+}
+
+impl RankFilter {
+    /// Index into the `count` sorted window samples of the sample the filter
+    /// outputs, or `None` for the trimmed mean
+    pub fn rank(self, count: u64) -> Option<u64> {
+        let basis_points = match self {
+            RankFilter::Median => 5000,
+            RankFilter::Min => 0,
+            RankFilter::Max => 10000,
+            RankFilter::Percentile(basis_points) => basis_points as u64,
+            RankFilter::TrimmedMean(_) => return None,
+        };
+        Some(((count - 1) * basis_points + 5000) / 10000)
+    }
+
+    /// Number of samples the trimmed mean drops from either end of `count`
+    /// sorted samples; at least one sample is always kept
+    pub fn trimmed(self, count: u64) -> u64 {
+        match self {
+            RankFilter::TrimmedMean(basis_points) => (count * basis_points as u64 / 10000).min((count - 1) / 2),
+            _ => 0,
+        }
+    }
+}
+
+impl FromStr for RankFilter {
+    type Err = String;
+
+    /// Parse a filter name; `percentile:<0-100>` and
+    /// `trimmed-mean:<0-0.5>` carry their parameter
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `per_unit` basis points per unit of the value, which lies in 0..=max
+        let basis_points = |value: &str, max: f64, per_unit: f64, what: &str| match value.parse::<f64>() {
+            Ok(v) if (0.0..=max).contains(&v) => Ok((v * per_unit).round() as u32),
+            _ => Err(format!("Invalid {} '{}', expected 0-{}", what, value, max)),
+        };
+
+        match s.split_once(':') {
+            None if s == "median" => Ok(RankFilter::Median),
+            None if s == "min" => Ok(RankFilter::Min),
+            None if s == "max" => Ok(RankFilter::Max),
+            Some(("percentile", value)) => {
+                basis_points(value, 100.0, 100.0, "percentile").map(RankFilter::Percentile)
+            }
+            Some(("trimmed-mean", value)) => {
+                basis_points(value, 0.5, 10000.0, "trim fraction").map(RankFilter::TrimmedMean)
+            }
+            _ => Err(format!(
+                "Unknown filter '{}'. Available: median, min, max, percentile:<p>, trimmed-mean:<alpha>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RankFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RankFilter::Median => write!(f, "median"),
+            RankFilter::Min => write!(f, "min"),
+            RankFilter::Max => write!(f, "max"),
+            RankFilter::Percentile(bp) => write!(f, "percentile:{}", *bp as f64 / 100.0),
+            RankFilter::TrimmedMean(bp) => write!(f, "trimmed-mean:{}", *bp as f64 / 10000.0),
+        }
+    }
+}
+
+/// Color layout of an image's pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    /// Single luminance channel
+    Gray,
+    /// Luminance and alpha channels
+    GrayAlpha,
+    /// Red, green and blue channels
+    Rgb,
+    /// Red, green, blue and alpha channels
+    Rgba,
+}
+
+impl ColorType {
+    /// Number of samples per pixel
+    pub fn channels(self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    /// Color type with the given number of samples per pixel
+    pub fn from_channels(channels: usize) -> Option<Self> {
+        match channels {
+            1 => Some(ColorType::Gray),
+            2 => Some(ColorType::GrayAlpha),
+            3 => Some(ColorType::Rgb),
+            4 => Some(ColorType::Rgba),
+            _ => None,
+        }
+    }
+
+    /// Index of the alpha channel, which always comes last
+    pub fn alpha_channel(self) -> Option<usize> {
+        match self {
+            ColorType::GrayAlpha | ColorType::Rgba => Some(self.channels() - 1),
+            ColorType::Gray | ColorType::Rgb => None,
+        }
+    }
+
+    /// Number of channels excluding alpha
+    pub fn color_channels(self) -> usize {
+        self.alpha_channel().unwrap_or(self.channels())
+    }
+}
+
+impl fmt::Display for ColorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorType::Gray => write!(f, "gray"),
+            ColorType::GrayAlpha => write!(f, "gray-alpha"),
+            ColorType::Rgb => write!(f, "rgb"),
+            ColorType::Rgba => write!(f, "rgba"),
+        }
+    }
+}
+
+/// How the alpha channel of images with transparency is filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaMode {
+    /// Median-filter alpha like any other channel
+    #[default]
+    Filter,
+    /// Copy alpha to the output unchanged
+    Keep,
+    /// Keep alpha unchanged and take the color medians only over neighbors
+    /// whose alpha is at least the given value (on the 8-bit scale)
+    Opaque(u8),
+}
+
+impl AlphaMode {
+    /// Default alpha threshold of `opaque`: at least half opaque
+    pub const DEFAULT_THRESHOLD: u8 = 128;
+}
+
+impl FromStr for AlphaMode {
+    type Err = String;
+
+    /// Parse a mode name; `opaque` may carry a threshold as `opaque:<0-255>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "filter" => Ok(AlphaMode::Filter),
+            "keep" => Ok(AlphaMode::Keep),
+            "opaque" => Ok(AlphaMode::Opaque(AlphaMode::DEFAULT_THRESHOLD)),
+            _ => match s.strip_prefix("opaque:") {
+                Some(value) => value
+                    .parse()
+                    .map(AlphaMode::Opaque)
+                    .map_err(|_| format!("Invalid alpha threshold '{}'", value)),
+                None => Err(format!(
+                    "Unknown alpha mode '{}'. Available: filter, keep, opaque[:threshold]",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for AlphaMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlphaMode::Filter => write!(f, "filter"),
+            AlphaMode::Keep => write!(f, "keep"),
+            AlphaMode::Opaque(threshold) => write!(f, "opaque:{}", threshold),
+        }
+    }
+}
 
 /// Wrapper around image data for easier manipulation
-pub struct Image {
-    pub data: RgbImage,
+///
+/// Pixels are stored row by row with their channels interleaved, so a
+/// grayscale image holds one sample per pixel. `Image` without a sample
+/// type is the 8-bit image of the original API.
+#[derive(Clone)]
+pub struct Image<T: Sample = u8> {
+    pub data: Vec<T>,
     pub width: u32,
     pub height: u32,
+    pub color: ColorType,
+}
+
+impl<T: Sample> Image<T> {
+    /// Save image to file, keeping its color type and bit depth
+    ///
+    /// 16-bit images need a format such as PNG or TIFF, float images one
+    /// such as TIFF or OpenEXR. Grayscale float images are saved as RGB(A).
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), image::ImageError> {
+        if T::TYPE == SampleType::F32 && self.color.color_channels() == 1 {
+            let rgb = Image {
+                data: self
+                    .data
+                    .chunks_exact(self.channels())
+                    .flat_map(|p| [p[0]; 3].into_iter().chain(p.get(1).copied()))
+                    .collect(),
+                width: self.width,
+                height: self.height,
+                color: ColorType::from_channels(self.channels() + 2).unwrap(),
+            };
+            return rgb.save(path);
+        }
+
+        image::save_buffer(
+            path,
+            bytemuck::cast_slice(&self.data),
+            self.width,
+            self.height,
+            T::extended_color(self.color),
+        )
+    }
+
+    /// Create a new black RGB image
+    pub fn new_empty(width: u32, height: u32) -> Self {
+        Image::new(width, height, ColorType::Rgb)
+    }
+
+    /// Create a new black image of the given color type
+    pub fn new(width: u32, height: u32, color: ColorType) -> Self {
+        Image {
+            data: vec![T::default(); width as usize * height as usize * color.channels()],
+            width,
+            height,
+            color,
+        }
+    }
+
+    /// Assemble an image from one plane per channel of `color`
+    pub fn from_planes(planes: Vec<Plane<T>>, color: ColorType) -> Self {
+        assert_eq!(planes.len(), color.channels(), "one plane per channel expected");
+        let (width, height) = (planes[0].width, planes[0].height);
+        let mut img = Image::new(width, height, color);
+
+        for (channel, plane) in planes.iter().enumerate() {
+            let samples = img.data.iter_mut().skip(channel).step_by(color.channels());
+            for (sample, &value) in samples.zip(&plane.data) {
+                *sample = value;
+            }
+        }
+
+        img
+    }
+
+    /// Number of samples per pixel
+    pub fn channels(&self) -> usize {
+        self.color.channels()
+    }
+
+    /// Get pixel at (x, y), one sample per channel
+    pub fn get_pixel(&self, x: u32, y: u32) -> &[T] {
+        let i = self.index(x, y);
+        &self.data[i..i + self.channels()]
+    }
+
+    /// Set pixel at (x, y)
+    pub fn put_pixel(&mut self, x: u32, y: u32, pixel: &[T]) {
+        let i = self.index(x, y);
+        let channels = self.channels();
+        self.data[i..i + channels].copy_from_slice(pixel);
+    }
+
+    /// Copy out one channel as a separate plane
+    pub fn plane(&self, channel: usize) -> Plane<T> {
+        Plane {
+            data: self.data.iter().skip(channel).step_by(self.channels()).copied().collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels()
+    }
 }
 
-impl Image {
-    /// Load image from file
+impl Image<u8> {
+    /// Load image from file as 8-bit RGB
+    ///
+    /// Every file is converted to RGB, as the library always did;
+    /// `AnyImage::load` keeps the file's channels and bit depth.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
-        let img = image::open(path)?;
-        let rgb_img = img.to_rgb8();
-        let (width, height) = rgb_img.dimensions();
-        
+        let img = image::open(path)?.to_rgb8();
+        let (width, height) = img.dimensions();
+
         Ok(Image {
-            data: rgb_img,
+            data: img.into_raw(),
             width,
             height,
+            color: ColorType::Rgb,
+        })
+    }
+}
+
+/// Image of any supported sample type, as loaded from a file
+///
+/// Backends take and return this type and filter the typed image inside.
+#[derive(Clone)]
+pub enum AnyImage {
+    U8(Image<u8>),
+    U16(Image<u16>),
+    F32(Image<f32>),
+}
+
+/// Evaluate `$body` with `$img` bound to the typed image inside an `AnyImage`
+macro_rules! with_any_image {
+    ($any:expr, $img:ident => $body:expr) => {
+        match $any {
+            $crate::shared::AnyImage::U8($img) => $body,
+            $crate::shared::AnyImage::U16($img) => $body,
+            $crate::shared::AnyImage::F32($img) => $body,
+        }
+    };
+}
+
+/// Like `with_any_image!`, wrapping the image `$body` returns into the
+/// variant it came from
+macro_rules! map_any_image {
+    ($any:expr, $img:ident => $body:expr) => {
+        match $any {
+            $crate::shared::AnyImage::U8($img) => $crate::shared::AnyImage::U8($body),
+            $crate::shared::AnyImage::U16($img) => $crate::shared::AnyImage::U16($body),
+            $crate::shared::AnyImage::F32($img) => $crate::shared::AnyImage::F32($body),
+        }
+    };
+}
+
+pub(crate) use map_any_image;
+
+impl AnyImage {
+    /// Load image from file, keeping its bit depth
+    ///
+    /// 8-bit files load as `u8`, 16-bit files as `u16` and float files as
+    /// `f32`. Grayscale files stay grayscale and files with transparency
+    /// keep their alpha channel; everything else is converted to RGB.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
+        let img = image::open(path)?;
+        let (width, height) = (img.width(), img.height());
+        let color = match (img.color().has_color(), img.color().has_alpha()) {
+            (false, false) => ColorType::Gray,
+            (false, true) => ColorType::GrayAlpha,
+            (true, false) => ColorType::Rgb,
+            (true, true) => ColorType::Rgba,
+        };
+        let bytes_per_sample = img.color().bytes_per_pixel() / img.color().channel_count();
+
+        Ok(match bytes_per_sample {
+            1 => AnyImage::U8(Image {
+                data: match color {
+                    ColorType::Gray => img.to_luma8().into_raw(),
+                    ColorType::GrayAlpha => img.to_luma_alpha8().into_raw(),
+                    ColorType::Rgb => img.to_rgb8().into_raw(),
+                    ColorType::Rgba => img.to_rgba8().into_raw(),
+                },
+                width,
+                height,
+                color,
+            }),
+            2 => AnyImage::U16(Image {
+                data: match color {
+                    ColorType::Gray => img.to_luma16().into_raw(),
+                    ColorType::GrayAlpha => img.to_luma_alpha16().into_raw(),
+                    ColorType::Rgb => img.to_rgb16().into_raw(),
+                    ColorType::Rgba => img.to_rgba16().into_raw(),
+                },
+                width,
+                height,
+                color,
+            }),
+            _ => AnyImage::F32(Image {
+                data: match color {
+                    ColorType::Gray => img.to_luma32f().into_raw(),
+                    ColorType::GrayAlpha => img.to_luma_alpha32f().into_raw(),
+                    ColorType::Rgb => img.to_rgb32f().into_raw(),
+                    ColorType::Rgba => img.to_rgba32f().into_raw(),
+                },
+                width,
+                height,
+                color,
+            }),
         })
     }
 
-    /// Save image to file
+    /// Save image to file, keeping its color type and bit depth
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), image::ImageError> {
-        self.data.save(path)
+        with_any_image!(self, img => img.save(path))
     }
 
-    /// Create a new image with the same dimensions
-    pub fn new_empty(width: u32, height: u32) -> Self {
-        Image {
-            data: RgbImage::new(width, height),
-            width,
-            height,
+    pub fn width(&self) -> u32 {
+        with_any_image!(self, img => img.width)
+    }
+
+    pub fn height(&self) -> u32 {
+        with_any_image!(self, img => img.height)
+    }
+
+    pub fn color(&self) -> ColorType {
+        with_any_image!(self, img => img.color)
+    }
+
+    pub fn sample_type(&self) -> SampleType {
+        match self {
+            AnyImage::U8(_) => SampleType::U8,
+            AnyImage::U16(_) => SampleType::U16,
+            AnyImage::F32(_) => SampleType::F32,
         }
     }
 
-    /// Get pixel at (x, y)
-    pub fn get_pixel(&self, x: u32, y: u32) -> &Rgb<u8> {
-        self.data.get_pixel(x, y)
+    /// Add salt-and-pepper noise, see `add_noise_with_rng`
+    pub fn add_noise<R: Rng + ?Sized>(&mut self, noise_level: f32, rng: &mut R) {
+        with_any_image!(self, img => add_noise_with_rng(img, noise_level, rng))
     }
 
-    /// Set pixel at (x, y)
-    pub fn put_pixel(&mut self, x: u32, y: u32, pixel: Rgb<u8>) {
-        self.data.put_pixel(x, y, pixel);
+    /// Add noise and return the mask of corrupted pixels, see `noise::apply`
+    pub fn apply_noise<R: Rng + ?Sized>(&mut self, noise: &NoiseSpec, rng: &mut R) -> Image<u8> {
+        with_any_image!(self, img => noise::apply(img, noise, rng))
     }
 
-    /// Get pixel with mirror padding for out-of-bounds coordinates
-    pub fn get_pixel_padded(&self, x: i32, y: i32) -> Rgb<u8> {
-        let px = if x < 0 {
-            (-x) as u32
-        } else if x >= self.width as i32 {
-            (2 * self.width as i32 - x - 2) as u32
-        } else {
-            x as u32
-        };
+    /// PSNR against `processed`, see `calculate_psnr`
+    pub fn psnr(&self, processed: &AnyImage) -> f64 {
+        self.psnr_in(processed, None)
+    }
 
-        let py = if y < 0 {
-            (-y) as u32
-        } else if y >= self.height as i32 {
-            (2 * self.height as i32 - y - 2) as u32
-        } else {
-            y as u32
-        };
+    /// PSNR against `processed` over the pixels set in `mask` only, see
+    /// `calculate_psnr_in`
+    pub fn psnr_in(&self, processed: &AnyImage, mask: Option<&[bool]>) -> f64 {
+        match (self, processed) {
+            (AnyImage::U8(a), AnyImage::U8(b)) => calculate_psnr_in(a, b, mask),
+            (AnyImage::U16(a), AnyImage::U16(b)) => calculate_psnr_in(a, b, mask),
+            (AnyImage::F32(a), AnyImage::F32(b)) => calculate_psnr_in(a, b, mask),
+            _ => panic!("Images must have the same sample type"),
+        }
+    }
+
+    /// Number of pixels that differ from `other`, see `count_changed_pixels`
+    pub fn changed_pixels(&self, other: &AnyImage) -> usize {
+        match (self, other) {
+            (AnyImage::U8(a), AnyImage::U8(b)) => count_changed_pixels(a, b),
+            (AnyImage::U16(a), AnyImage::U16(b)) => count_changed_pixels(a, b),
+            (AnyImage::F32(a), AnyImage::F32(b)) => count_changed_pixels(a, b),
+            _ => panic!("Images must have the same sample type"),
+        }
+    }
+
+    /// Luma of every pixel as a grayscale image, see `color::luminance`
+    pub fn luminance(&self) -> AnyImage {
+        map_any_image!(self, img => color::luminance(img))
+    }
+
+    /// SSIM against `processed`, see `calculate_ssim`
+    pub fn ssim(&self, processed: &AnyImage) -> f64 {
+        self.ssim_in(processed, None)
+    }
+
+    /// SSIM against `processed` over the pixels set in `mask` only, see
+    /// `calculate_ssim_in`
+    pub fn ssim_in(&self, processed: &AnyImage, mask: Option<&[bool]>) -> f64 {
+        match (self, processed) {
+            (AnyImage::U8(a), AnyImage::U8(b)) => calculate_ssim_in(a, b, mask),
+            (AnyImage::U16(a), AnyImage::U16(b)) => calculate_ssim_in(a, b, mask),
+            (AnyImage::F32(a), AnyImage::F32(b)) => calculate_ssim_in(a, b, mask),
+            _ => panic!("Images must have the same sample type"),
+        }
+    }
+}
+
+/// One channel of an image
+///
+/// Median filters treat channels independently, so the filter algorithms
+/// work on planes and the backends split images into them.
+pub struct Plane<T: Sample = u8> {
+    pub data: Vec<T>,
+    pub width: u32,
+    pub height: u32,
+}
 
-        let px = px.min(self.width - 1);
-        let py = py.min(self.height - 1);
+impl<T: Sample> Plane<T> {
+    /// Get sample at (x, y)
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.data[y as usize * self.width as usize + x as usize]
+    }
 
-        *self.get_pixel(px, py)
+    /// Get sample at possibly out-of-bounds coordinates, padded according to `border`
+    ///
+    /// Returns `None` only for `BorderMode::Shrink` outside the image.
+    pub fn get_padded(&self, x: i32, y: i32, border: BorderMode) -> Option<T> {
+        match (border.resolve(x, self.width), border.resolve(y, self.height)) {
+            (Some(px), Some(py)) => Some(self.get(px, py)),
+            _ => match border {
+                BorderMode::Constant(value) => Some(T::from_u8(value)),
+                _ => None,
+            },
+        }
     }
 }
 
+/// Random number generator for the noise functions, seeded with `seed`
+///
+/// ChaCha8 is named explicitly because `StdRng` may switch algorithms
+/// between rand releases; with the same rand version, the same seed gives
+/// the same noise on every machine and backend.
+pub fn noise_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
 /// Add salt-and-pepper noise to the image
 /// noise_level: 0.0 to 1.0 (percentage of pixels to corrupt)
-pub fn add_noise(img: &mut Image, noise_level: f32) {
-    let mut rng = rand::thread_rng();
-    let total_pixels = (img.width * img.height) as f32;
-    let pixels_to_corrupt = (total_pixels * noise_level) as u32;
+///
+/// Draws from the thread's random generator, so every call gives different
+/// noise; `add_noise_with_rng` takes a seeded generator instead.
+pub fn add_noise<T: Sample>(img: &mut Image<T>, noise_level: f32) {
+    add_noise_with_rng(img, noise_level, &mut rand::rng());
+}
 
-    for _ in 0..pixels_to_corrupt {
-        let x = rng.gen_range(0..img.width);
-        let y = rng.gen_range(0..img.height);
-        
-        // Randomly choose salt (white) or pepper (black)
-        let value = if rng.gen_bool(0.5) { 255 } else { 0 };
-        let pixel = Rgb([value, value, value]);
-        
-        img.put_pixel(x, y, pixel);
-    }
+/// Add salt-and-pepper noise to the image, drawing from `rng`
+///
+/// Coordinates are drawn with replacement, so repeated hits leave fewer
+/// corrupted pixels than requested; `noise::apply` has an exact mode, other
+/// noise models and returns the corrupted pixels.
+pub fn add_noise_with_rng<T: Sample, R: Rng + ?Sized>(img: &mut Image<T>, noise_level: f32, rng: &mut R) {
+    noise::apply(img, &NoiseSpec::new(noise_level), rng);
 }
 
 /// Calculate median of a slice of values
-pub fn median(values: &mut [u8]) -> u8 {
-    values.sort_unstable();
+pub fn median<T: Sample>(values: &mut [T]) -> T {
+    values.sort_unstable_by(T::total_cmp);
     values[values.len() / 2]
 }
 
-/// Calculate median for RGB pixel (per channel)
-pub fn median_rgb(pixels: &[Rgb<u8>]) -> Rgb<u8> {
-    let mut r_values: Vec<u8> = pixels.iter().map(|p| p[0]).collect();
-    let mut g_values: Vec<u8> = pixels.iter().map(|p| p[1]).collect();
-    let mut b_values: Vec<u8> = pixels.iter().map(|p| p[2]).collect();
+/// Collect the samples covered by the kernel for median filtering
+///
+/// With `BorderMode::Shrink` out-of-bounds samples are skipped, so fewer than
+/// `kernel.len()` values may be returned. If none remain (a custom mask
+/// without its center, entirely outside the image), the center sample is used.
+pub fn collect_neighborhood<T: Sample>(
+    plane: &Plane<T>,
+    x: u32,
+    y: u32,
+    kernel: &Kernel,
+    border: BorderMode,
+) -> Vec<T> {
+    let mut values: Vec<T> = kernel
+        .offsets()
+        .iter()
+        .filter_map(|&(dx, dy)| plane.get_padded(x as i32 + dx, y as i32 + dy, border))
+        .collect();
+
+    if values.is_empty() {
+        values.push(plane.get(x, y));
+    }
+
+    values
+}
+
+/// Median-filter rows `rows` of `plane` as described by `spec`
+///
+/// Returns the filtered samples of those rows in row-major order. Rows of the
+/// window above and below `rows` are read from `plane`, so callers splitting
+/// an image into bands get the same result as filtering it whole.
+pub fn filter_rows<T: Sample>(plane: &Plane<T>, spec: &FilterSpec, rows: Range<u32>) -> Vec<T> {
+    if let Some(max_size) = spec.adaptive {
+        return adaptive::adaptive_rows(plane, spec, max_size, rows);
+    }
+    if let Some(weights) = &spec.weights {
+        return weighted_rows(plane, None, spec, weights, rows);
+    }
+
+    let algorithm = spec.resolve_algorithm(T::TYPE);
+    if matches!(algorithm, Algorithm::Auto | Algorithm::Sort) {
+        return sort_rows(plane, spec, rows);
+    }
+
+    // The remaining algorithms exist for 8-bit samples only, which
+    // `FilterSpec::validate_samples` checks before filtering
+    let plane = (plane as &dyn Any)
+        .downcast_ref::<Plane<u8>>()
+        .unwrap_or_else(|| panic!("the {} algorithm needs 8-bit samples", algorithm));
+    let output = match algorithm {
+        Algorithm::ConstantTime => {
+            histogram::constant_time_rows(plane, &spec.kernel, spec.rank, spec.border, rows)
+        }
+        Algorithm::Huang => histogram::huang_rows(plane, &spec.kernel, spec.rank, spec.border, rows),
+        Algorithm::Simd => simd::simd_rows(plane, &spec.kernel, spec.border, rows),
+        Algorithm::Auto | Algorithm::Sort => unreachable!(),
+    };
+    *(Box::new(output) as Box<dyn Any>).downcast::<Vec<T>>().unwrap()
+}
+
+/// Filter rows `rows` of every channel of an image of `color` type, given as
+/// one plane per channel
+///
+/// Returns the filtered samples of those rows, one vector per channel.
+/// Channels are filtered separately unless the spec selects the vector median.
+pub fn filter_image_rows<T: Sample>(
+    planes: &[Plane<T>],
+    color: ColorType,
+    spec: &FilterSpec,
+    rows: Range<u32>,
+) -> Vec<Vec<T>> {
+    match spec.vector {
+        Some(norm) => vector::vector_median_rows(planes, color, spec, norm, rows),
+        None => (0..planes.len())
+            .map(|channel| filter_channel_rows(planes, channel, color, spec, rows.clone()))
+            .collect(),
+    }
+}
+
+/// Filter rows `rows` of channel `channel` of an image of `color` type,
+/// given as one plane per channel, applying the spec's alpha mode and
+/// channel selection
+///
+/// Like `filter_rows`, returns the samples of those rows in row-major order.
+/// Channels the spec does not filter are copied.
+pub fn filter_channel_rows<T: Sample>(
+    planes: &[Plane<T>],
+    channel: usize,
+    color: ColorType,
+    spec: &FilterSpec,
+    rows: Range<u32>,
+) -> Vec<T> {
+    let plane = &planes[channel];
+    if !spec.filters_channel(channel, color) {
+        let width = plane.width as usize;
+        return plane.data[rows.start as usize * width..rows.end as usize * width].to_vec();
+    }
+
+    match (color.alpha_channel(), spec.alpha) {
+        (Some(alpha), AlphaMode::Opaque(threshold)) => {
+            let threshold = T::from_u8(threshold);
+            match &spec.weights {
+                Some(weights) => {
+                    let opaque = Some((&planes[alpha], threshold));
+                    weighted_rows(plane, opaque, spec, weights, rows)
+                }
+                None => opaque_rows(plane, &planes[alpha], threshold, spec, rows),
+            }
+        }
+        _ => filter_rows(plane, spec, rows),
+    }
+}
+
+/// Sort-based median of rows `rows` over the neighbors whose `alpha` is at
+/// least `threshold`
+fn opaque_rows<T: Sample>(
+    plane: &Plane<T>,
+    alpha: &Plane<T>,
+    threshold: T,
+    spec: &FilterSpec,
+    rows: Range<u32>,
+) -> Vec<T> {
+    let (kernel, border) = (&spec.kernel, spec.border);
+    let mut samples = WindowSamples::with_filter(kernel, spec.rank);
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
+
+    for y in rows {
+        for x in 0..plane.width {
+            output.push(samples.opaque_median_at(plane, alpha, threshold, x, y, kernel, border));
+        }
+    }
+
+    output
+}
+
+/// Weighted median of rows `rows`, optionally over the neighbors whose alpha
+/// is at least a threshold, see `WindowSamples::weighted_median_at`
+fn weighted_rows<T: Sample>(
+    plane: &Plane<T>,
+    opaque: Option<(&Plane<T>, T)>,
+    spec: &FilterSpec,
+    weights: &[u32],
+    rows: Range<u32>,
+) -> Vec<T> {
+    let (kernel, border) = (&spec.kernel, spec.border);
+    let mut samples = WindowSamples::with_filter(kernel, spec.rank);
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
 
-    Rgb([
-        median(&mut r_values),
-        median(&mut g_values),
-        median(&mut b_values),
-    ])
+    for y in rows {
+        for x in 0..plane.width {
+            output.push(samples.weighted_median_at(plane, opaque, x, y, kernel, weights, border));
+        }
+    }
+
+    output
 }
 
-/// Collect neighborhood pixels for median filtering
-pub fn collect_neighborhood(img: &Image, x: u32, y: u32, kernel_size: usize) -> Vec<Rgb<u8>> {
-    let radius = (kernel_size / 2) as i32;
-    let mut pixels = Vec::with_capacity(kernel_size * kernel_size);
+/// Sort-based rank filter of rows `rows`; the median matches `median` over
+/// `collect_neighborhood`
+///
+/// The sample buffer is allocated once per call and reused for every pixel,
+/// so each thread filtering a band of rows works without allocating.
+fn sort_rows<T: Sample>(plane: &Plane<T>, spec: &FilterSpec, rows: Range<u32>) -> Vec<T> {
+    let (kernel, border) = (&spec.kernel, spec.border);
+    let mut samples = WindowSamples::with_filter(kernel, spec.rank);
+    let mut output = Vec::with_capacity(plane.width as usize * rows.len());
 
-    for dy in -radius..=radius {
-        for dx in -radius..=radius {
-            let px = x as i32 + dx;
-            let py = y as i32 + dy;
-            pixels.push(img.get_pixel_padded(px, py));
+    for y in rows {
+        for x in 0..plane.width {
+            output.push(samples.median_at(plane, x, y, kernel, border));
         }
     }
 
-    pixels
+    output
 }
 
 /// Calculate PSNR (Peak Signal-to-Noise Ratio) between two images
 /// Higher is better, typical values: 20-50 dB
-pub fn calculate_psnr(original: &Image, processed: &Image) -> f64 {
-    if original.width != processed.width || original.height != processed.height {
-        panic!("Images must have the same dimensions");
-    }
+///
+/// The peak signal is the white level of the sample type: 255, 65535 or 1.0.
+pub fn calculate_psnr<T: Sample>(original: &Image<T>, processed: &Image<T>) -> f64 {
+    calculate_psnr_in(original, processed, None)
+}
 
-    let mut mse = 0.0;
-    let total_pixels = (original.width * original.height) as f64;
+/// PSNR over the pixels set in `mask` (one flag per pixel, row by row), or
+/// over all pixels without a mask
+pub fn calculate_psnr_in<T: Sample>(original: &Image<T>, processed: &Image<T>, mask: Option<&[bool]>) -> f64 {
+    check_comparable(original, processed);
 
-    for y in 0..original.height {
-        for x in 0..original.width {
-            let orig = original.get_pixel(x, y);
-            let proc = processed.get_pixel(x, y);
+    let channels = original.channels();
+    let mut mse = 0.0;
+    let mut samples = 0;
 
-            for c in 0..3 {
-                let diff = orig[c] as f64 - proc[c] as f64;
-                mse += diff * diff;
-            }
+    let pixels = original.data.chunks_exact(channels).zip(processed.data.chunks_exact(channels));
+    for (i, (orig, proc)) in pixels.enumerate() {
+        if mask.is_some_and(|mask| !mask[i]) {
+            continue;
         }
+        for (&orig, &proc) in orig.iter().zip(proc) {
+            let diff = orig.to_f64() - proc.to_f64();
+            mse += diff * diff;
+        }
+        samples += channels;
     }
 
-    mse /= total_pixels * 3.0; // 3 channels
+    mse /= samples as f64; // every channel of every pixel
 
     if mse == 0.0 {
         f64::INFINITY
     } else {
-        20.0 * (255.0_f64).log10() - 10.0 * mse.log10()
+        20.0 * T::MAX.to_f64().log10() - 10.0 * mse.log10()
     }
 }
 
+/// Count the pixels in which any channel (alpha included) differs between
+/// two images
+pub fn count_changed_pixels<T: Sample>(original: &Image<T>, processed: &Image<T>) -> usize {
+    check_comparable(original, processed);
+
+    let channels = original.channels();
+    original
+        .data
+        .chunks_exact(channels)
+        .zip(processed.data.chunks_exact(channels))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.total_cmp(b).is_ne()))
+        .count()
+}
+
 /// Calculate SSIM (Structural Similarity Index) between two images
 /// Range: -1 to 1, where 1 means identical images
 /// Typical good values: > 0.9
-pub fn calculate_ssim(original: &Image, processed: &Image) -> f64 {
-    if original.width != processed.width || original.height != processed.height {
-        panic!("Images must have the same dimensions");
-    }
+pub fn calculate_ssim<T: Sample>(original: &Image<T>, processed: &Image<T>) -> f64 {
+    calculate_ssim_in(original, processed, None)
+}
 
-    // Constants for SSIM calculation
-    let c1 = (0.01_f64 * 255.0_f64).powi(2);
-    let c2 = (0.03_f64 * 255.0_f64).powi(2);
+/// SSIM over the pixels set in `mask` (one flag per pixel, row by row), or
+/// over all pixels without a mask
+///
+/// Window statistics use the masked pixels only; windows without any are
+/// skipped.
+pub fn calculate_ssim_in<T: Sample>(original: &Image<T>, processed: &Image<T>, mask: Option<&[bool]>) -> f64 {
+    check_comparable(original, processed);
+    let in_mask = |x: u32, y: u32| mask.is_none_or(|mask| mask[(y * original.width + x) as usize]);
+
+    // Constants for SSIM calculation, relative to the sample type's range
+    let peak = T::MAX.to_f64();
+    let c1 = (0.01_f64 * peak).powi(2);
+    let c2 = (0.03_f64 * peak).powi(2);
 
     let mut ssim_sum = 0.0;
     let mut count = 0;
@@ -191,13 +1085,13 @@ pub fn calculate_ssim(original: &Image, processed: &Image) -> f64 {
 
             // First pass: calculate means
             for wy in y..max_y {
-                for wx in x..max_x {
+                for wx in (x..max_x).filter(|&wx| in_mask(wx, wy)) {
                     let p1 = original.get_pixel(wx, wy);
                     let p2 = processed.get_pixel(wx, wy);
 
-                    // Average across RGB channels
-                    let v1 = (p1[0] as f64 + p1[1] as f64 + p1[2] as f64) / 3.0;
-                    let v2 = (p2[0] as f64 + p2[1] as f64 + p2[2] as f64) / 3.0;
+                    // Average across channels
+                    let v1 = channel_mean(p1);
+                    let v2 = channel_mean(p2);
 
                     mean1 += v1;
                     mean2 += v2;
@@ -205,17 +1099,21 @@ pub fn calculate_ssim(original: &Image, processed: &Image) -> f64 {
                 }
             }
 
+            if window_pixels == 0 {
+                continue;
+            }
+
             mean1 /= window_pixels as f64;
             mean2 /= window_pixels as f64;
 
             // Second pass: calculate variances and covariance
             for wy in y..max_y {
-                for wx in x..max_x {
+                for wx in (x..max_x).filter(|&wx| in_mask(wx, wy)) {
                     let p1 = original.get_pixel(wx, wy);
                     let p2 = processed.get_pixel(wx, wy);
 
-                    let v1 = (p1[0] as f64 + p1[1] as f64 + p1[2] as f64) / 3.0;
-                    let v2 = (p2[0] as f64 + p2[1] as f64 + p2[2] as f64) / 3.0;
+                    let v1 = channel_mean(p1);
+                    let v2 = channel_mean(p2);
 
                     let diff1 = v1 - mean1;
                     let diff2 = v2 - mean2;
@@ -242,3 +1140,15 @@ pub fn calculate_ssim(original: &Image, processed: &Image) -> f64 {
     ssim_sum / count as f64
 }
 
+fn channel_mean<T: Sample>(pixel: &[T]) -> f64 {
+    pixel.iter().map(|&v| v.to_f64()).sum::<f64>() / pixel.len() as f64
+}
+
+fn check_comparable<T: Sample>(original: &Image<T>, processed: &Image<T>) {
+    if original.width != processed.width || original.height != processed.height {
+        panic!("Images must have the same dimensions");
+    }
+    if original.color != processed.color {
+        panic!("Images must have the same color type");
+    }
+}
@@ -0,0 +1,207 @@
+//! SIMD median for 3x3 and 5x5 windows.
+//!
+//! Runs the comparator networks from `selection` on vectors of horizontally
+//! adjacent pixels, one channel at a time, so a single min/max instruction
+//! advances 16 or 32 medians. The instruction set is detected at runtime:
+//! AVX2 or SSE2 on x86_64, NEON on aarch64. Pixels whose window crosses the
+//! image border, and every pixel on other CPUs, use the scalar selection.
+
+use crate::kernel::Kernel;
+use crate::selection::{WindowSamples, MEDIAN_25, MEDIAN_9};
+use crate::shared::{BorderMode, Plane};
+use std::ops::Range;
+
+/// Computes `out.len()` medians of one channel plane, for consecutive pixels
+/// starting at index `center`. `offsets` are the window samples relative to
+/// the center, `out.len()` is a multiple of the vector width.
+type RowMedians = unsafe fn(plane: &[u8], center: usize, offsets: &[isize], out: &mut [u8]);
+
+/// Whether the SIMD median can run on `kernel`
+pub fn supports(kernel: &Kernel) -> bool {
+    kernel.is_full() && kernel.width() == kernel.height() && matches!(kernel.width(), 3 | 5)
+}
+
+/// Name of the instruction set `simd_rows` uses on this CPU
+pub fn instruction_set() -> &'static str {
+    detect().map_or("scalar", |(name, _, _)| name)
+}
+
+fn network(len: usize) -> &'static [(usize, usize)] {
+    match len {
+        9 => &MEDIAN_9,
+        25 => &MEDIAN_25,
+        _ => unreachable!("SIMD median supports 3x3 and 5x5 windows only"),
+    }
+}
+
+/// Generate a `RowMedians` implementation for one instruction set
+///
+/// The operations are spliced in as expressions rather than closures so they
+/// are compiled with the function's target features.
+macro_rules! row_medians {
+    (
+        $name:ident, $feature:literal, $lanes:literal, zero: $zero:expr,
+        load: |$lp:ident| $load:expr,
+        store: |$sp:ident, $sv:ident| $store:expr,
+        min: |$na:ident, $nb:ident| $min:expr,
+        max: |$xa:ident, $xb:ident| $max:expr $(,)?
+    ) => {
+        #[target_feature(enable = $feature)]
+        unsafe fn $name(plane: &[u8], center: usize, offsets: &[isize], out: &mut [u8]) {
+            let network = network(offsets.len());
+            let mut p = [$zero; 25];
+
+            for start in (0..out.len()).step_by($lanes) {
+                let base = plane.as_ptr().add(center + start);
+                for (v, &offset) in p.iter_mut().zip(offsets) {
+                    let $lp = base.offset(offset);
+                    *v = $load;
+                }
+                for &(a, b) in network {
+                    let lo = {
+                        let ($na, $nb) = (p[a], p[b]);
+                        $min
+                    };
+                    p[b] = {
+                        let ($xa, $xb) = (p[a], p[b]);
+                        $max
+                    };
+                    p[a] = lo;
+                }
+                let $sp = out.as_mut_ptr().add(start);
+                let $sv = p[offsets.len() / 2];
+                $store;
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::network;
+    use std::arch::x86_64::*;
+
+    row_medians!(
+        row_medians_avx2, "avx2", 32, zero: _mm256_setzero_si256(),
+        load: |p| _mm256_loadu_si256(p as *const __m256i),
+        store: |p, v| _mm256_storeu_si256(p as *mut __m256i, v),
+        min: |a, b| _mm256_min_epu8(a, b),
+        max: |a, b| _mm256_max_epu8(a, b),
+    );
+
+    row_medians!(
+        row_medians_sse2, "sse2", 16, zero: _mm_setzero_si128(),
+        load: |p| _mm_loadu_si128(p as *const __m128i),
+        store: |p, v| _mm_storeu_si128(p as *mut __m128i, v),
+        min: |a, b| _mm_min_epu8(a, b),
+        max: |a, b| _mm_max_epu8(a, b),
+    );
+
+    pub(super) fn detect() -> Option<(&'static str, usize, super::RowMedians)> {
+        if is_x86_feature_detected!("avx2") {
+            Some(("avx2", 32, row_medians_avx2))
+        } else if is_x86_feature_detected!("sse2") {
+            Some(("sse2", 16, row_medians_sse2))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use super::network;
+    use std::arch::aarch64::*;
+
+    row_medians!(
+        row_medians_neon, "neon", 16, zero: vdupq_n_u8(0),
+        load: |p| vld1q_u8(p),
+        store: |p, v| vst1q_u8(p, v),
+        min: |a, b| vminq_u8(a, b),
+        max: |a, b| vmaxq_u8(a, b),
+    );
+
+    pub(super) fn detect() -> Option<(&'static str, usize, super::RowMedians)> {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            Some(("neon", 16, row_medians_neon))
+        } else {
+            None
+        }
+    }
+}
+
+/// Instruction set name, vector width and row kernel for this CPU, if any
+fn detect() -> Option<(&'static str, usize, RowMedians)> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        x86::detect()
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        arm::detect()
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        None
+    }
+}
+
+/// Median-filter rows `rows` of `plane`, vectorizing pixels whose window
+/// lies inside the image
+///
+/// `kernel` must be a full 3x3 or 5x5 window (see `supports`). The result is
+/// identical to the scalar selection for every border mode.
+pub fn simd_rows(plane: &Plane, kernel: &Kernel, border: BorderMode, rows: Range<u32>) -> Vec<u8> {
+    debug_assert!(supports(kernel), "SIMD median needs a 3x3 or 5x5 window");
+
+    let width = plane.width as usize;
+    let radius = kernel.width() / 2;
+    let mut samples = WindowSamples::new(kernel);
+    let mut output = Vec::with_capacity(width * rows.len());
+
+    // Rows and columns whose window stays inside the image, vectorized in
+    // whole multiples of the vector width
+    let inner_rows = rows.start.max(radius as u32)..rows.end.min(plane.height.saturating_sub(radius as u32));
+    let (vector_end, row_medians) = match detect() {
+        Some((_, lanes, row_medians)) if !inner_rows.is_empty() && width >= 2 * radius + lanes => {
+            (radius + (width - 2 * radius) / lanes * lanes, Some(row_medians))
+        }
+        _ => (radius, None),
+    };
+
+    let offsets: Vec<isize> = kernel
+        .offsets()
+        .iter()
+        .map(|&(dx, dy)| dy as isize * width as isize + dx as isize)
+        .collect();
+
+    for y in rows {
+        match row_medians.filter(|_| inner_rows.contains(&y)) {
+            Some(row_medians) => {
+                for x in 0..radius {
+                    output.push(samples.median_at(plane, x as u32, y, kernel, border));
+                }
+
+                let start = output.len();
+                output.resize(start + vector_end - radius, 0);
+                // SAFETY: the instruction set was detected at runtime, and
+                // every window of columns radius..vector_end on an inner row
+                // lies inside the plane
+                unsafe {
+                    row_medians(&plane.data, y as usize * width + radius, &offsets, &mut output[start..])
+                };
+
+                for x in vector_end..width {
+                    output.push(samples.median_at(plane, x as u32, y, kernel, border));
+                }
+            }
+            None => {
+                for x in 0..width {
+                    output.push(samples.median_at(plane, x as u32, y, kernel, border));
+                }
+            }
+        }
+    }
+
+    output
+}
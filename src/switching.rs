@@ -0,0 +1,238 @@
+//! Switching median filter.
+//!
+//! Salt-and-pepper noise corrupts only some pixels, yet a median filter
+//! replaces every pixel with its window median and so also alters clean
+//! ones. A switching filter runs an impulse detector first and takes the
+//! median only for the pixels it marks as noise; all other pixels keep their
+//! input value.
+//!
+//! Detection needs only a pixel and its window median, so every backend
+//! wraps its filter in `filter_switched`, which switches between the
+//! medians and the input.
+
+use crate::backend::{FilterError, FilterSpec};
+use crate::region::Region;
+use crate::sample::Sample;
+use crate::shared::{AnyImage, ColorType, Image};
+use std::fmt;
+use std::str::FromStr;
+
+/// Test deciding whether a pixel is an impulse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpulseDetector {
+    /// Sample at black or white, the two values salt-and-pepper noise sets
+    Extreme,
+    /// Sample differs from the window median by more than the threshold
+    /// (given on the 8-bit scale and scaled to the image's sample type)
+    RankOrder(u8),
+}
+
+impl ImpulseDetector {
+    /// Default rank-order difference threshold, on the 8-bit scale
+    pub const DEFAULT_THRESHOLD: u8 = 40;
+
+    /// Whether `center` is an impulse given its window median
+    pub fn is_impulse<T: Sample>(self, center: T, median: T) -> bool {
+        match self {
+            ImpulseDetector::Extreme => {
+                center.total_cmp(&T::default()).is_le() || center.total_cmp(&T::MAX).is_ge()
+            }
+            ImpulseDetector::RankOrder(threshold) => {
+                (center.to_f64() - median.to_f64()).abs() > T::from_u8(threshold).to_f64()
+            }
+        }
+    }
+}
+
+impl FromStr for ImpulseDetector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "extreme" => Ok(ImpulseDetector::Extreme),
+            None if s == "rank-order" => Ok(ImpulseDetector::RankOrder(Self::DEFAULT_THRESHOLD)),
+            Some(("rank-order", threshold)) => threshold
+                .parse()
+                .map(ImpulseDetector::RankOrder)
+                .map_err(|_| format!("Invalid rank-order threshold '{}', expected 0-255", threshold)),
+            _ => Err(format!(
+                "Unknown impulse detector '{}'. Available: extreme, rank-order[:threshold]",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ImpulseDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImpulseDetector::Extreme => write!(f, "extreme"),
+            ImpulseDetector::RankOrder(threshold) => write!(f, "rank-order:{}", threshold),
+        }
+    }
+}
+
+/// Mark the pixels of `noisy` that `detector` finds to be impulses, one flag
+/// per pixel in row-major order
+///
+/// `median` is `noisy` median-filtered. A pixel is an impulse if any of its
+/// color channels is; alpha is not tested.
+pub fn detect_impulses<T: Sample>(noisy: &Image<T>, median: &Image<T>, detector: ImpulseDetector) -> Vec<bool> {
+    let channels = noisy.channels();
+    let color_channels = noisy.color.color_channels();
+    noisy
+        .data
+        .chunks_exact(channels)
+        .zip(median.data.chunks_exact(channels))
+        .map(|(pixel, med)| (0..color_channels).any(|c| detector.is_impulse(pixel[c], med[c])))
+        .collect()
+}
+
+/// Take the pixels marked in `mask` from `median` and all others from `noisy`
+pub fn switch<T: Sample>(noisy: &Image<T>, median: &Image<T>, mask: &[bool]) -> Image<T> {
+    let channels = noisy.channels();
+    let mut output = noisy.clone();
+    let pixels = output.data.chunks_exact_mut(channels).zip(median.data.chunks_exact(channels));
+
+    for ((pixel, med), &impulse) in pixels.zip(mask) {
+        if impulse {
+            pixel.copy_from_slice(med);
+        }
+    }
+
+    output
+}
+
+/// Noise mask as a grayscale image: white for impulses, black elsewhere
+pub fn mask_image(mask: &[bool], width: u32, height: u32) -> Image<u8> {
+    Image {
+        data: mask.iter().map(|&impulse| if impulse { u8::MAX } else { 0 }).collect(),
+        width,
+        height,
+        color: ColorType::Gray,
+    }
+}
+
+/// Combine two noise masks of the same size: a pixel is an impulse if
+/// either mask marks it
+pub fn union_mask(mut mask: Image<u8>, other: &Image<u8>) -> Image<u8> {
+    for (value, &other) in mask.data.iter_mut().zip(&other.data) {
+        *value = (*value).max(other);
+    }
+    mask
+}
+
+/// Run `filter` so that it replaces only the pixels the spec's impulse
+/// detector marks, within the spec's region; all others keep their input
+/// value
+///
+/// Images are passed to `filter` unchanged when the spec has no detector.
+/// The extreme detector tests the input alone, so its impulses become the
+/// region `filter` is limited to. The rank-order detector compares each
+/// pixel with its window median, so the median is taken everywhere and kept
+/// only for the impulses.
+pub fn filter_switched<F>(img: &AnyImage, spec: &FilterSpec, filter: F) -> Result<AnyImage, FilterError>
+where
+    F: FnOnce(&AnyImage, &FilterSpec) -> Result<AnyImage, FilterError>,
+{
+    let Some(detector) = spec.switching else {
+        return filter(img, spec);
+    };
+
+    if detector == ImpulseDetector::Extreme {
+        let (width, height) = (img.width(), img.height());
+        let mut pixels = match img {
+            AnyImage::U8(img) => detect_impulses(img, img, detector),
+            AnyImage::U16(img) => detect_impulses(img, img, detector),
+            AnyImage::F32(img) => detect_impulses(img, img, detector),
+        };
+        if let Some(region) = &spec.region {
+            region.validate(width, height)?;
+            pixels.iter_mut().zip(region.pixels(width, height)).for_each(|(impulse, inside)| *impulse &= inside);
+        }
+
+        // An image without impulses is filtered as usual below and comes
+        // back unchanged, so MPI workers still get their part of it
+        if pixels.contains(&true) {
+            let region = Region::Mask { width, height, pixels };
+            return filter(img, &FilterSpec { region: Some(region), ..spec.clone() });
+        }
+    }
+
+    let median = filter(img, spec)?;
+    Ok(match (img, &median) {
+        (AnyImage::U8(i), AnyImage::U8(m)) => AnyImage::U8(switch(i, m, &detect_impulses(i, m, detector))),
+        (AnyImage::U16(i), AnyImage::U16(m)) => AnyImage::U16(switch(i, m, &detect_impulses(i, m, detector))),
+        (AnyImage::F32(i), AnyImage::F32(m)) => AnyImage::F32(switch(i, m, &detect_impulses(i, m, detector))),
+        _ => unreachable!("filtering keeps the sample type"),
+    })
+}
+
+/// Noise mask of a switching filter run: the pixels of `noisy` that
+/// `detector` marks as impulses, within `region`
+///
+/// `filtered` is the output of `filter_switched`. It holds the window median
+/// of every impulse and the input value of every other pixel, so testing
+/// against it marks the same pixels as testing against the medians.
+pub fn noise_mask(
+    noisy: &AnyImage,
+    filtered: &AnyImage,
+    detector: ImpulseDetector,
+    region: Option<&Region>,
+) -> Image<u8> {
+    let (width, height) = (noisy.width(), noisy.height());
+    let mut mask = match (noisy, filtered) {
+        (AnyImage::U8(n), AnyImage::U8(f)) => detect_impulses(n, f, detector),
+        (AnyImage::U16(n), AnyImage::U16(f)) => detect_impulses(n, f, detector),
+        (AnyImage::F32(n), AnyImage::F32(f)) => detect_impulses(n, f, detector),
+        _ => panic!("Images must have the same sample type"),
+    };
+    if let Some(region) = region {
+        mask.iter_mut().zip(region.pixels(width, height)).for_each(|(impulse, inside)| *impulse &= inside);
+    }
+    mask_image(&mask, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::create_backend;
+    use crate::test_util::impulse_image;
+
+    /// Every backend replaces the pixels the detector flags with their
+    /// median and leaves every other pixel at its input value
+    #[test]
+    fn only_detected_pixels_are_replaced() {
+        let noisy = impulse_image(13, 9);
+        let img = AnyImage::U8(noisy.clone());
+        let AnyImage::U8(median) = create_backend("seq").unwrap().filter(&img, &FilterSpec::new(3)).unwrap() else {
+            unreachable!("filtering keeps the sample type")
+        };
+
+        for detector in [ImpulseDetector::Extreme, ImpulseDetector::RankOrder(40)] {
+            let flags = detect_impulses(&noisy, &median, detector);
+            assert!(flags.contains(&true) && flags.contains(&false));
+            let spec = FilterSpec { switching: Some(detector), ..FilterSpec::new(3) };
+
+            for name in ["seq", "par", "gpu"] {
+                let switched = match create_backend(name).unwrap().filter(&img, &spec) {
+                    Ok(AnyImage::U8(switched)) => switched,
+                    Err(FilterError::Gpu(msg)) if msg.starts_with("Failed to find GPU adapter") => continue,
+                    other => panic!("{} backend failed: {:?}", name, other.err()),
+                };
+                for (i, &impulse) in flags.iter().enumerate() {
+                    let expected = if impulse { &median } else { &noisy };
+                    assert_eq!(
+                        &switched.data[i * 3..i * 3 + 3],
+                        &expected.data[i * 3..i * 3 + 3],
+                        "{} with {}: pixel {}, impulse: {}",
+                        name,
+                        detector,
+                        i,
+                        impulse
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Fixtures shared by the unit tests.
+
+use crate::shared::{ColorType, Image};
+
+/// Xorshift32 generator for test fixtures, so every run tests the same
+/// samples without depending on the `rand` version
+pub(crate) struct XorShift(u32);
+
+impl XorShift {
+    /// Generator starting from `seed`, which must not be zero
+    pub(crate) fn new(seed: u32) -> Self {
+        XorShift(seed)
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// RGB test image of smooth gradients with scattered salt-and-pepper
+/// impulses: about one sample in eight is 0 and one in eight is 255
+pub(crate) fn impulse_image(width: u32, height: u32) -> Image {
+    let mut img = Image::new(width, height, ColorType::Rgb);
+    let mut rng = XorShift::new(0x2545_f491);
+    for (i, sample) in img.data.iter_mut().enumerate() {
+        *sample = match rng.next_u32() % 8 {
+            0 => 0,
+            1 => 255,
+            _ => (i * 7 % 256) as u8,
+        };
+    }
+    img
+}
@@ -0,0 +1,164 @@
+//! Vector median filter for multi-channel images.
+//!
+//! Instead of taking the median of every channel separately, which can
+//! produce colors that appear nowhere in the window, the vector median picks
+//! the window pixel with the smallest sum of distances to all other window
+//! pixels. Every output pixel is therefore one of the input pixels.
+
+use crate::backend::FilterSpec;
+use crate::sample::Sample;
+use crate::shared::{AlphaMode, ColorType, Plane, VectorNorm};
+use std::ops::Range;
+
+/// Largest kernel size of the vector median
+///
+/// Every pixel compares all pairs of its window pixels, O(k⁴) distances for
+/// a k×k kernel: 15×15 already takes about 25 000 per pixel.
+pub const MAX_KERNEL_SIZE: usize = 15;
+
+/// Vector-median-filter rows `rows` of an image of `color` type, given as
+/// one plane per channel
+///
+/// Distances are measured over the color channels. The alpha channel is
+/// copied from the selected pixel (`AlphaMode::Filter`) or from the center
+/// pixel; with `AlphaMode::Opaque` only sufficiently opaque neighbors are
+/// candidates. With kernel weights, each distance to a pixel counts as many
+/// times as its weight. Returns the filtered samples of those rows, one vector per
+/// channel. Ties go to the pixel that comes first in the kernel.
+pub fn vector_median_rows<T: Sample>(
+    planes: &[Plane<T>],
+    color: ColorType,
+    spec: &FilterSpec,
+    norm: VectorNorm,
+    rows: Range<u32>,
+) -> Vec<Vec<T>> {
+    let channels = planes.len();
+    let color_channels = color.color_channels();
+    let width = planes[0].width;
+    let alpha = color.alpha_channel();
+    let threshold = match (alpha, spec.alpha) {
+        (Some(_), AlphaMode::Opaque(threshold)) => Some(T::from_u8(threshold)),
+        _ => None,
+    };
+
+    let mut output = vec![Vec::with_capacity(width as usize * rows.len()); channels];
+    // Window pixels with their channels interleaved, their weights and their
+    // distance sums
+    let mut window: Vec<T> = Vec::with_capacity(spec.kernel.len() * channels);
+    let mut weights: Vec<f64> = Vec::with_capacity(spec.kernel.len());
+    let mut sums: Vec<f64> = Vec::with_capacity(spec.kernel.len());
+
+    for y in rows {
+        for x in 0..width {
+            window.clear();
+            weights.clear();
+            for (i, &(dx, dy)) in spec.kernel.offsets().iter().enumerate() {
+                let (px, py) = (x as i32 + dx, y as i32 + dy);
+                let weight = spec.weights.as_ref().map_or(1, |w| w[i]);
+                if weight == 0 {
+                    continue;
+                }
+
+                // Shrink skips pixels outside the image in every channel at once
+                let Some(first) = planes[0].get_padded(px, py, spec.border) else {
+                    continue;
+                };
+                if let (Some(alpha), Some(threshold)) = (alpha, threshold) {
+                    let a = planes[alpha].get_padded(px, py, spec.border).unwrap();
+                    if a.total_cmp(&threshold).is_lt() {
+                        continue;
+                    }
+                }
+
+                window.push(first);
+                weights.push(weight as f64);
+                window.extend(planes[1..].iter().map(|p| p.get_padded(px, py, spec.border).unwrap()));
+            }
+
+            let selected = if window.is_empty() {
+                None
+            } else {
+                let pixels = window.len() / channels;
+                sums.clear();
+                sums.resize(pixels, 0.0);
+                for i in 0..pixels {
+                    for j in i + 1..pixels {
+                        let a = &window[i * channels..i * channels + color_channels];
+                        let b = &window[j * channels..j * channels + color_channels];
+                        let d = norm.distance(a, b);
+                        sums[i] += weights[j] * d;
+                        sums[j] += weights[i] * d;
+                    }
+                }
+
+                let best = (1..pixels).fold(0, |best, i| if sums[i] < sums[best] { i } else { best });
+                Some(&window[best * channels..(best + 1) * channels])
+            };
+
+            // Nothing left under the footprint: keep the center pixel
+            for (channel, out) in output.iter_mut().enumerate() {
+                let from_center = selected.is_none()
+                    || (Some(channel) == alpha && spec.alpha != AlphaMode::Filter);
+                out.push(match selected {
+                    Some(pixel) if !from_center => pixel[channel],
+                    _ => planes[channel].get(x, y),
+                });
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FilterError;
+    use crate::shared::BorderMode;
+    use crate::test_util::impulse_image;
+
+    /// Every output pixel is one of the pixels under its window, whole: the
+    /// vector median never mixes channels of different pixels
+    #[test]
+    fn output_pixels_come_from_the_window() {
+        let img = impulse_image(11, 8);
+        let planes: Vec<Plane> = (0..img.channels()).map(|c| img.plane(c)).collect();
+        let border = BorderMode::Reflect101;
+
+        for norm in [VectorNorm::L1, VectorNorm::L2] {
+            for kernel_size in [3, 5] {
+                let mut spec = FilterSpec::new(kernel_size);
+                spec.border = border;
+                let output = vector_median_rows(&planes, img.color, &spec, norm, 0..img.height);
+
+                for y in 0..img.height {
+                    for x in 0..img.width {
+                        let i = (y * img.width + x) as usize;
+                        let pixel: Vec<u8> = output.iter().map(|channel| channel[i]).collect();
+                        let found = spec.kernel.offsets().iter().any(|&(dx, dy)| {
+                            let px = border.resolve(x as i32 + dx, img.width).unwrap();
+                            let py = border.resolve(y as i32 + dy, img.height).unwrap();
+                            img.get_pixel(px, py) == pixel.as_slice()
+                        });
+                        assert!(
+                            found,
+                            "{:?} at ({}, {}) is not in its {}x{} window",
+                            pixel, x, y, kernel_size, kernel_size
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_kernels_are_rejected() {
+        let mut spec = FilterSpec::new(MAX_KERNEL_SIZE);
+        spec.vector = Some(VectorNorm::L1);
+        assert!(spec.validate(127).is_ok());
+
+        spec = FilterSpec::new(MAX_KERNEL_SIZE + 2);
+        spec.vector = Some(VectorNorm::L1);
+        assert!(matches!(spec.validate(127), Err(FilterError::Unsupported(_))));
+    }
+}